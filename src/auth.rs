@@ -1,61 +1,332 @@
 use std::collections::HashMap;
 
-use axol::{Error, IntoResponse, Query, Result, Typed};
-use axol_http::{header::TypedHeader, typed_headers::SetCookie};
+use anyhow::bail;
+use axol::{ConnectInfo, Error, IntoResponse, Query, Result, Typed};
+use axol_http::{header::HeaderMap, typed_headers::Cookie as CookieHeader, StatusCode};
 use chrono::Utc;
 use cookie::{Cookie, CookieBuilder};
+use openid::Bearer;
 use serde::Deserialize;
-use serde_json::Value;
-use tracing::warn;
+use tracing::{error, info, warn};
 use url::Url;
 
 use crate::{
-    config::{CONFIG, REDIRECT_URL},
+    chunks,
+    claims::{
+        allowed_claims, claim_values, granted_scopes, header_value, normalize_email,
+        normalize_email_claim, resolve, template_claims,
+    },
+    config::{live, CallbackErrorMode, CONFIG, REDIRECT_URL},
+    flow::{random_token, FlowError, FlowState},
     jwt::JwtClaims,
     jwtc::compress,
-    oidc::OIDC,
+    login::{login_url, retry_login_url},
+    metrics::OVERSIZED_COOKIES,
+    oidc::{provider, TokenEndpointError},
+    response::{status_error, status_response},
+    seal::seal,
+    validate::{session_state, SessionState},
 };
 
 #[derive(Deserialize)]
 pub struct OauthParameters {
-    code: String,
+    code: Option<String>,
+    /// Set instead of `code` when the provider aborted the login.
+    error: Option<String>,
+    error_description: Option<String>,
     url: Url,
+    state: Option<String>,
+    /// RFC 9207 issuer identification, sent by IdPs that support it.
+    iss: Option<String>,
 }
 
-pub fn build_cookie(claims: &JwtClaims, max_age: i64) -> anyhow::Result<Cookie<'static>> {
+/// Compares a callback `iss` to the configured issuer, ignoring a trailing slash.
+fn issuer_matches(expected: &Url, iss: &str) -> bool {
+    expected.as_str().trim_end_matches('/') == iss.trim_end_matches('/')
+}
+
+/// Drops the tokens the session doesn't need to keep, to save cookie space.
+pub fn strip_tokens(bearer: &mut Bearer) {
+    if !CONFIG.logout_with_hint {
+        bearer.id_token.take();
+    }
+    if CONFIG.role_refresh_sec.is_none() && !CONFIG.forward_access_token {
+        bearer.access_token = "".to_string();
+    }
+}
+
+/// Session cookies for `claims`: a single cookie, or `<cookie_name>.0`, `.1`, ... if it exceeds `cookie_max_size`.
+/// `present` are the cookie names sent with the request, so session cookies of a previous layout get expired.
+pub fn build_cookies(
+    claims: &JwtClaims,
+    max_age: i64,
+    present: &[&str],
+    secure: bool,
+) -> anyhow::Result<Vec<Cookie<'static>>> {
     let signed = claims.sign()?;
-    let value = compress(&signed)?;
-    let cookie = CookieBuilder::new(&CONFIG.cookie_name, value)
+    let mut value = compress(&signed)?;
+    if CONFIG.cookie_encrypt {
+        value = seal(&value)?;
+    }
+    let pieces = chunks::split(&value, CONFIG.cookie_max_size);
+    let names = chunks::names(&CONFIG.cookie_name, pieces.len());
+    let size = names.iter().map(|x| x.len()).sum::<usize>() + value.len();
+    if size > CONFIG.max_total_cookie_bytes {
+        OVERSIZED_COOKIES.inc();
+        bail!(
+            "session cookie is {size} bytes, exceeding max_total_cookie_bytes ({}): the IdP tokens are too large for cookie storage",
+            CONFIG.max_total_cookie_bytes
+        );
+    }
+    let mut cookies = names
+        .iter()
+        .zip(pieces)
+        .map(|(name, piece)| session_cookie(name.clone(), piece.to_string(), max_age, secure))
+        .collect::<Vec<_>>();
+    for stale in chunks::stale(present.iter().copied(), &CONFIG.cookie_name, &names) {
+        cookies.push(session_cookie(stale, String::new(), 0, secure));
+    }
+    Ok(cookies)
+}
+
+/// Expires every session cookie, chunked or not. Domain, path and secure must match `build_cookies` or browsers keep the original.
+pub fn clear_cookies(present: &[&str], secure: bool) -> Vec<Cookie<'static>> {
+    let base = vec![CONFIG.cookie_name.clone()];
+    let stale = chunks::stale(present.iter().copied(), &CONFIG.cookie_name, &base);
+    base.into_iter()
+        .chain(stale)
+        .map(|name| session_cookie(name, String::new(), 0, secure))
+        .collect()
+}
+
+/// Names of the cookies sent with a request.
+pub fn cookie_names(cookies: Option<&CookieHeader>) -> Vec<&str> {
+    cookies
+        .map(|x| x.iter().map(|(name, _)| name).collect())
+        .unwrap_or_default()
+}
+
+/// Whether cookies set in reply to this request are Secure, per `cookie_secure`.
+pub fn cookie_secure(headers: &HeaderMap) -> bool {
+    CONFIG
+        .cookie_secure
+        .resolve(headers.get("x-forwarded-proto"))
+        || CONFIG.cookie_host_prefixed()
+}
+
+fn session_cookie(name: String, value: String, max_age: i64, secure: bool) -> Cookie<'static> {
+    let mut cookie = CookieBuilder::new(name, value)
         .http_only(true)
-        .secure(CONFIG.cookie_secure)
+        .secure(secure)
+        .same_site(CONFIG.cookie_same_site.into())
         .max_age(cookie::time::Duration::seconds(max_age))
-        .domain(&CONFIG.cookie_domain)
-        .path("/")
-        .finish();
-    Ok(cookie)
+        .path(CONFIG.cookie_path.clone());
+    if !CONFIG.cookie_domain.is_empty() && !CONFIG.cookie_host_prefixed() {
+        cookie = cookie.domain(CONFIG.cookie_domain.clone());
+    }
+    cookie.finish()
+}
+
+/// What a callback carries: a `code` to exchange, or the provider's `error` with its optional description.
+enum Callback<'a> {
+    Code(&'a str),
+    Error(&'a str, Option<&'a str>),
+}
+
+impl OauthParameters {
+    fn callback(&self) -> Result<Callback<'_>> {
+        match (&self.error, &self.code) {
+            (Some(error), _) => Ok(Callback::Error(error, self.error_description.as_deref())),
+            (None, Some(code)) => Ok(Callback::Code(code)),
+            (None, None) => {
+                warn!("callback has neither code nor error");
+                Err(Error::bad_request("missing code"))
+            }
+        }
+    }
+}
+
+/// Responds to a callback carrying a provider `error` per `mode`, ending the login flow by sending `clear_flow`.
+/// Redirects go to `access_denied_url` if set, since the target url would only send the user back to log in.
+fn callback_error(
+    mode: CallbackErrorMode,
+    url: Url,
+    access_denied_url: Option<&Url>,
+    error: &str,
+    description: Option<&str>,
+    clear_flow: String,
+) -> Result<(HeaderMap, Url)> {
+    match mode {
+        CallbackErrorMode::Redirect => {
+            let mut url = match access_denied_url {
+                Some(access_denied_url) => {
+                    let mut access_denied_url = access_denied_url.clone();
+                    access_denied_url
+                        .query_pairs_mut()
+                        .append_pair("url", url.as_str());
+                    access_denied_url
+                }
+                None => url,
+            };
+            {
+                let mut pairs = url.query_pairs_mut();
+                pairs.append_pair("error", error);
+                if let Some(description) = description {
+                    pairs.append_pair("error_description", description);
+                }
+            }
+            let mut headers = HeaderMap::new();
+            headers.insert("set-cookie", clear_flow);
+            Ok((headers, url))
+        }
+        CallbackErrorMode::Page => {
+            let mut response = status_response(StatusCode::Unauthorized);
+            response
+                .headers
+                .insert("content-type", "text/plain; charset=utf-8");
+            response.headers.insert("set-cookie", clear_flow);
+            response.body = match description {
+                Some(description) => format!("sign-in failed: {error}: {description}\n"),
+                None => format!("sign-in failed: {error}\n"),
+            }
+            .into();
+            Err(Error::Response(response))
+        }
+    }
 }
 
-pub async fn auth(Query(query): Query<OauthParameters>) -> Result<impl IntoResponse> {
+pub async fn auth(
+    Query(query): Query<OauthParameters>,
+    cookies: Option<Typed<CookieHeader>>,
+    headers_in: HeaderMap,
+    connect_info: ConnectInfo,
+) -> Result<impl IntoResponse> {
+    if !CONFIG.is_redirect_allowed(&query.url) {
+        warn!(url = %query.url, "redirect not allowed");
+        return Err(Error::bad_request("redirect not allowed"));
+    }
+    let secure = cookie_secure(&headers_in);
+    // the browser is navigating back to `url`, which is a GET
+    let live = live();
+    let customized = live.customized_url(&query.url, Some("GET"), connect_info.ip());
+    let provider_name = CONFIG.provider_for_url(&query.url);
+    let oidc = provider(provider_name);
+    match &query.iss {
+        Some(iss) if !issuer_matches(&oidc.provider.issuer, iss) => {
+            warn!(iss = %iss, "callback issuer mismatch, possible IdP mix-up");
+            return Err(Error::unauthorized("bad issuer"));
+        }
+        None if CONFIG.require_callback_issuer => {
+            warn!("callback has no iss parameter and require_callback_issuer is set");
+            return Err(Error::unauthorized("missing issuer"));
+        }
+        _ => (),
+    }
+
+    let flow = match FlowState::from_cookies(cookies.as_ref().map(|x| &x.0)) {
+        Ok(flow) => flow,
+        Err(FlowError::Expired) => {
+            info!("auth flow too old, restarting login");
+            return Ok((HeaderMap::new(), login_url(&query.url)));
+        }
+        Err(FlowError::Missing) => {
+            warn!("callback without a valid login flow cookie");
+            return Err(Error::bad_request("missing login flow"));
+        }
+    };
+    if !flow.matches(query.state.as_deref().unwrap_or_default(), &query.url) {
+        warn!(url = %query.url, "callback state does not match the login flow");
+        return Err(Error::unauthorized("bad state"));
+    }
+
+    let code = match query.callback()? {
+        Callback::Code(code) => code,
+        Callback::Error(error, description) => {
+            warn!(
+                oauth.error = %error,
+                oauth.error_description = ?description,
+                url = %query.url,
+                "provider returned an error to the callback"
+            );
+            return callback_error(
+                CONFIG.callback_error,
+                query.url.clone(),
+                CONFIG.access_denied_url.as_ref(),
+                error,
+                description,
+                FlowState::clear_cookie(secure).encoded().to_string(),
+            );
+        }
+    };
+
     let mut redirect_uri = REDIRECT_URL.clone();
     redirect_uri
         .query_pairs_mut()
         .append_pair("url", query.url.as_str());
 
-    let (mut bearer, claims) = OIDC
-        .validate_code(&redirect_uri, &query.code)
+    let (mut bearer, claims) = match oidc
+        .validate_code(
+            &redirect_uri,
+            code,
+            &flow.verifier,
+            &flow.nonce,
+            customized.client,
+        )
         .await
-        .map_err(|e| {
-            warn!("failed to validate claims: {e:#}");
-            Error::unauthorized("bad oauth code")
-        })?;
-    let roles = claims
-        .realm_access
-        .as_ref()
-        .map(|x| &x.roles[..])
-        .unwrap_or_default()
-        .to_vec();
-
-    let raw_userinfo = serde_json::to_value(claims.standard.userinfo).map_err(Error::internal)?;
+    {
+        Ok(x) => x,
+        Err(e) => match TokenEndpointError::find(&e) {
+            Some(oauth) if oauth.is_invalid_client() => {
+                error!(
+                    oauth.error = %oauth.error,
+                    oauth.error_description = ?oauth.description,
+                    "token endpoint rejected our client credentials, check client_id/client_secret: {e:#}"
+                );
+                return Err(status_error(StatusCode::ServiceUnavailable));
+            }
+            Some(oauth) if oauth.is_invalid_grant() && flow.retried => {
+                // a persistent cause, like a mismatched redirect_uri or clock skew, would loop through the IdP forever
+                error!(
+                    oauth.error = %oauth.error,
+                    oauth.error_description = ?oauth.description,
+                    "authorization code rejected again after restarting login, giving up: {e:#}"
+                );
+                return Err(Error::unauthorized("authorization code rejected"));
+            }
+            Some(oauth) if oauth.is_invalid_grant() => {
+                warn!(
+                    oauth.error = %oauth.error,
+                    oauth.error_description = ?oauth.description,
+                    "authorization code rejected, restarting login: {e:#}"
+                );
+                return Ok((HeaderMap::new(), retry_login_url(&query.url)));
+            }
+            Some(oauth) => {
+                warn!(
+                    oauth.error = %oauth.error,
+                    oauth.error_description = ?oauth.description,
+                    "failed to validate claims: {e:#}"
+                );
+                return Err(Error::unauthorized("bad oauth code"));
+            }
+            None => {
+                warn!("failed to validate claims: {e:#}");
+                return Err(Error::unauthorized("bad oauth code"));
+            }
+        },
+    };
+    if CONFIG.require_sub && claims.standard.sub.trim().is_empty() {
+        error!("id_token has no `sub` claim, refusing to create an identity-less session. check the IdP client configuration");
+        return Err(Error::unauthorized("missing sub"));
+    }
+    let raw_claims = serde_json::to_value(&claims).map_err(Error::internal)?;
+    let roles = CONFIG.session_roles(&raw_claims);
+    let scopes = granted_scopes(&raw_claims, bearer.scope.as_deref(), &oidc.provider.scopes);
+    let claim_values = claim_values(&raw_claims, live.required_claim_names());
+    let mut userinfo = allowed_claims(&raw_claims, &CONFIG.userinfo_header_claims);
+    if CONFIG.normalize_email {
+        normalize_email_claim(&mut userinfo);
+    }
     let now = Utc::now().timestamp();
     let mut max_age = CONFIG.login_cache_minutes * 60;
     if CONFIG.honor_token_expiry {
@@ -65,9 +336,11 @@ pub async fn auth(Query(query): Query<OauthParameters>) -> Result<impl IntoRespo
             }
         }
     }
+    if let Some(absolute) = CONFIG.absolute_session_seconds {
+        max_age = max_age.min(absolute);
+    }
 
-    bearer.id_token.take();
-    bearer.access_token = "".to_string();
+    strip_tokens(&mut bearer);
     if !CONFIG.refresh_tokens {
         bearer.refresh_token.take();
     }
@@ -77,28 +350,158 @@ pub async fn auth(Query(query): Query<OauthParameters>) -> Result<impl IntoRespo
         iss: now,
         exp: now + max_age,
         roles,
+        roles_at: now,
+        refreshed_at: now,
+        scopes,
+        claim_values,
+        userinfo,
+        sub: Some(claims.standard.sub.clone()).filter(|x| !x.is_empty()),
+        sid: raw_claims
+            .get("sid")
+            .and_then(|x| x.as_str())
+            .map(String::from),
+        jti: Some(random_token()),
+        login_at: now,
+        provider: provider_name.map(String::from),
+        client_id: customized.client.map(|x| x.client_id.clone()),
         bearer,
     };
-    for claim in CONFIG.header_claims.values() {
-        if let Some(value) = raw_userinfo.get(claim) {
-            let value = match value {
-                Value::Null => continue,
-                Value::Bool(b) => b.to_string(),
-                Value::Number(n) => n.to_string(),
-                Value::String(s) => s.clone(),
-                _ => {
-                    warn!("unserializable userinfo field: {claim}");
-                    continue;
-                }
-            };
-            claims.claims.insert(claim.clone(), value);
+    for claim in live.header_claims.values().flat_map(|x| template_claims(x)) {
+        let Some(value) = resolve(&raw_claims, claim) else {
+            continue;
+        };
+        let Some(mut value) = header_value(
+            value,
+            &CONFIG.header_claims_separator,
+            CONFIG.header_sanitize,
+        ) else {
+            continue;
+        };
+        if claim == "email" && CONFIG.normalize_email {
+            value = normalize_email(&value);
+        }
+        claims.claims.insert(claim.to_string(), value);
+    }
+
+    if let Some(access_denied_url) = &CONFIG.access_denied_url {
+        if let SessionState::Forbidden = session_state(&claims, &customized) {
+            info!(url = %query.url, "login cannot satisfy the target's requirements, sending to access_denied_url");
+            let mut access_denied_url = access_denied_url.clone();
+            access_denied_url
+                .query_pairs_mut()
+                .append_pair("url", query.url.as_str());
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                "set-cookie",
+                FlowState::clear_cookie(secure).encoded().to_string(),
+            );
+            return Ok((headers, access_denied_url));
         }
     }
 
-    let cookie = build_cookie(&claims, max_age).map_err(Error::internal)?;
+    let present = cookie_names(cookies.as_ref().map(|x| &x.0));
+    let session_cookies = build_cookies(&claims, max_age, &present, secure).map_err(|e| {
+        error!("failed to build session cookie: {e:#}");
+        Error::internal(e)
+    })?;
+
+    let mut headers = HeaderMap::new();
+    for cookie in session_cookies {
+        headers.append("set-cookie", cookie.encoded().to_string());
+    }
+    headers.append(
+        "set-cookie",
+        FlowState::clear_cookie(secure).encoded().to_string(),
+    );
+    Ok((headers, query.url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok((
-        Typed(SetCookie::decode(&cookie.encoded().to_string()).unwrap()),
-        query.url,
-    ))
+    fn parameters(
+        code: Option<&str>,
+        error: Option<&str>,
+        description: Option<&str>,
+    ) -> OauthParameters {
+        OauthParameters {
+            code: code.map(String::from),
+            error: error.map(String::from),
+            error_description: description.map(String::from),
+            url: Url::parse("https://app.my.domain/a").unwrap(),
+            state: Some("s".to_string()),
+            iss: None,
+        }
+    }
+
+    #[test]
+    fn test_callback() {
+        assert!(matches!(
+            parameters(Some("abc"), None, None).callback(),
+            Ok(Callback::Code("abc"))
+        ));
+        assert!(matches!(
+            parameters(None, Some("access_denied"), Some("nope")).callback(),
+            Ok(Callback::Error("access_denied", Some("nope")))
+        ));
+        // an error wins over a code sent alongside it
+        assert!(matches!(
+            parameters(Some("abc"), Some("access_denied"), None).callback(),
+            Ok(Callback::Error("access_denied", None))
+        ));
+        // neither: the 400 `missing code`
+        assert!(parameters(None, None, None).callback().is_err());
+    }
+
+    #[test]
+    fn test_callback_error() {
+        let url = Url::parse("https://app.my.domain/a").unwrap();
+        let denied = Url::parse("https://my.domain/denied").unwrap();
+        let clear = || "oiplease_flow=; Max-Age=0".to_string();
+
+        let Err(Error::Response(response)) = callback_error(
+            CallbackErrorMode::Page,
+            url.clone(),
+            Some(&denied),
+            "access_denied",
+            None,
+            clear(),
+        ) else {
+            panic!("page mode must respond directly");
+        };
+        assert_eq!(response.status, StatusCode::Unauthorized);
+        assert_eq!(response.headers.get("set-cookie"), Some(&*clear()));
+
+        let (headers, redirect) = callback_error(
+            CallbackErrorMode::Redirect,
+            url.clone(),
+            None,
+            "access_denied",
+            Some("no means no"),
+            clear(),
+        )
+        .ok()
+        .unwrap();
+        assert_eq!(
+            redirect.as_str(),
+            "https://app.my.domain/a?error=access_denied&error_description=no+means+no"
+        );
+        assert_eq!(headers.get("set-cookie"), Some(&*clear()));
+
+        let (_, redirect) = callback_error(
+            CallbackErrorMode::Redirect,
+            url,
+            Some(&denied),
+            "access_denied",
+            None,
+            clear(),
+        )
+        .ok()
+        .unwrap();
+        assert_eq!(
+            redirect.as_str(),
+            "https://my.domain/denied?url=https%3A%2F%2Fapp.my.domain%2Fa&error=access_denied"
+        );
+    }
 }
@@ -0,0 +1,47 @@
+use std::time::Instant;
+
+use anyhow::{ensure, Result};
+
+use crate::jwtc::{compress_with, decompress, Algorithm};
+
+const ITERATIONS: u32 = 1000;
+
+/// Reports the encoded size and round-trip timings of each cookie compression algorithm for a sample token.
+pub fn compression(token: &str) -> Result<()> {
+    let token = token.trim();
+    println!("input: {} bytes", token.len());
+    println!(
+        "{:<8} {:>10} {:>8} {:>14} {:>16}",
+        "algo", "bytes", "ratio", "compress us", "decompress us"
+    );
+    for algorithm in Algorithm::ALL {
+        let compressed = compress_with(token, *algorithm)?;
+        ensure!(
+            decompress(&compressed)? == token,
+            "{} failed to round-trip the token",
+            algorithm.name()
+        );
+
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            compress_with(token, *algorithm)?;
+        }
+        let compress_time = start.elapsed() / ITERATIONS;
+
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            decompress(&compressed)?;
+        }
+        let decompress_time = start.elapsed() / ITERATIONS;
+
+        println!(
+            "{:<8} {:>10} {:>8.3} {:>14.1} {:>16.1}",
+            algorithm.name(),
+            compressed.len(),
+            compressed.len() as f64 / token.len() as f64,
+            compress_time.as_secs_f64() * 1_000_000.0,
+            decompress_time.as_secs_f64() * 1_000_000.0,
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,146 @@
+//! Splitting session cookies that exceed the per-cookie size browsers accept into `<name>.0`, `<name>.1`, ...
+
+/// Splits an ASCII cookie value into pieces of at most `max` bytes. Always yields at least one piece.
+pub fn split(value: &str, max: usize) -> Vec<&str> {
+    if value.len() <= max {
+        return vec![value];
+    }
+    value
+        .as_bytes()
+        .chunks(max.max(1))
+        .map(|x| std::str::from_utf8(x).expect("cookie values are ascii"))
+        .collect()
+}
+
+pub fn chunk_name(base: &str, index: usize) -> String {
+    format!("{base}.{index}")
+}
+
+/// Cookie names to write a value of `count` pieces to: `base` alone, or one chunk name per piece.
+pub fn names(base: &str, count: usize) -> Vec<String> {
+    if count <= 1 {
+        vec![base.to_string()]
+    } else {
+        (0..count).map(|i| chunk_name(base, i)).collect()
+    }
+}
+
+fn chunk_index(base: &str, name: &str) -> Option<usize> {
+    name.strip_prefix(base)?.strip_prefix('.')?.parse().ok()
+}
+
+/// Most chunks a session can be written as, given `max_total_cookie_bytes` and `cookie_max_size`.
+pub fn max_chunks(max_total: usize, max: usize) -> usize {
+    max_total.div_ceil(max.max(1))
+}
+
+/// Reassembles `base.0`, `base.1`, ... from request cookies, if a complete run starting at 0 exists.
+/// A duplicated chunk name keeps its first value. Indices come from the client, so chunks at or past
+/// `max_chunks` are ignored rather than allocated for.
+pub fn reassemble<'a>(
+    cookies: impl IntoIterator<Item = (&'a str, &'a str)>,
+    base: &str,
+    max_chunks: usize,
+) -> Option<String> {
+    let mut chunks: Vec<Option<&str>> = vec![];
+    for (name, value) in cookies {
+        let Some(index) = chunk_index(base, name).filter(|x| *x < max_chunks) else {
+            continue;
+        };
+        if index >= chunks.len() {
+            chunks.resize(index + 1, None);
+        }
+        chunks[index].get_or_insert(value);
+    }
+    if chunks.is_empty() {
+        return None;
+    }
+    chunks
+        .into_iter()
+        .collect::<Option<Vec<_>>>()
+        .map(|x| x.concat())
+}
+
+/// Session cookie names present in the request that `written` doesn't overwrite, and so must be expired.
+pub fn stale<'a>(
+    present: impl IntoIterator<Item = &'a str>,
+    base: &str,
+    written: &[String],
+) -> Vec<String> {
+    let mut out: Vec<String> = vec![];
+    for name in present {
+        let ours = name == base || chunk_index(base, name).is_some();
+        if ours && !written.iter().any(|x| x == name) && !out.iter().any(|x| x == name) {
+            out.push(name.to_string());
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let value = "abcdefghij".repeat(1024);
+        let pieces = split(&value, 3800);
+        assert_eq!(pieces.len(), 3);
+        assert!(pieces.iter().all(|x| x.len() <= 3800));
+
+        let names = names("oiplease", pieces.len());
+        assert_eq!(names, vec!["oiplease.0", "oiplease.1", "oiplease.2"]);
+
+        // browsers don't preserve cookie order
+        let mut cookies = names
+            .iter()
+            .map(|x| &**x)
+            .zip(pieces.iter().copied())
+            .collect::<Vec<_>>();
+        cookies.reverse();
+        cookies.push(("other", "x"));
+        assert_eq!(reassemble(cookies.clone(), "oiplease", 4).unwrap(), value);
+        // the session doesn't fit the cap, so it can't have been written this way
+        assert_eq!(reassemble(cookies.clone(), "oiplease", 2), None);
+
+        cookies.retain(|(name, _)| *name != "oiplease.1");
+        assert_eq!(reassemble(cookies, "oiplease", 4), None);
+
+        assert_eq!(split("short", 3800), vec!["short"]);
+        assert_eq!(super::names("oiplease", 1), vec!["oiplease"]);
+    }
+
+    #[test]
+    fn test_oversized_index() {
+        let cookies = [
+            ("oiplease.0", "a"),
+            ("oiplease.1", "b"),
+            ("oiplease.999999999", "x"),
+            ("oiplease.18446744073709551615", "x"),
+        ];
+        assert_eq!(reassemble(cookies, "oiplease", 4).as_deref(), Some("ab"));
+        assert_eq!(reassemble([("oiplease.4", "x")], "oiplease", 4), None);
+        assert_eq!(max_chunks(16384, 3800), 5);
+        assert_eq!(max_chunks(7600, 3800), 2);
+    }
+
+    #[test]
+    fn test_stale() {
+        let present = [
+            "oiplease",
+            "oiplease.0",
+            "oiplease.1",
+            "oiplease.2",
+            "oiplease_flow",
+            "other",
+        ];
+        assert_eq!(
+            stale(present, "oiplease", &names("oiplease", 2)),
+            vec!["oiplease", "oiplease.2"]
+        );
+        assert_eq!(
+            stale(present, "oiplease", &names("oiplease", 1)),
+            vec!["oiplease.0", "oiplease.1", "oiplease.2"]
+        );
+    }
+}
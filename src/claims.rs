@@ -0,0 +1,491 @@
+use std::collections::HashMap;
+
+use serde_json::{Map, Value};
+use tracing::warn;
+
+use crate::config::{HeaderSanitize, MissingClaimPolicy, RolesClaimsMode};
+
+/// Resolves a dot-separated claim path such as `realm_access.roles`.
+pub fn resolve<'a>(raw: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.')
+        .try_fold(raw, |value, segment| value.get(segment))
+}
+
+/// Extracts roles from the configured claim paths, in order.
+/// `First` takes the first path yielding any role, `Merge` unions all of them.
+pub fn extract_roles(raw: &Value, paths: &[String], mode: RolesClaimsMode) -> Vec<String> {
+    let mut roles: Vec<String> = vec![];
+    for path in paths {
+        let found = resolve(raw, path).map(role_strings).unwrap_or_default();
+        if found.is_empty() {
+            continue;
+        }
+        match mode {
+            RolesClaimsMode::First => return found,
+            RolesClaimsMode::Merge => roles = merge_roles(roles, found),
+        }
+    }
+    roles
+}
+
+/// Keycloak client roles, `resource_access.<client_id>.roles`. Looked up directly since client ids may contain dots.
+pub fn resource_roles(raw: &Value, client_id: &str) -> Vec<String> {
+    raw.get("resource_access")
+        .and_then(|x| x.get(client_id))
+        .and_then(|x| x.get("roles"))
+        .map(role_strings)
+        .unwrap_or_default()
+}
+
+/// Adds `extra` roles not already present.
+pub fn merge_roles(mut roles: Vec<String>, extra: Vec<String>) -> Vec<String> {
+    for role in extra {
+        if !roles.contains(&role) {
+            roles.push(role);
+        }
+    }
+    roles
+}
+
+/// Like `claim_strings`, but a single string is a space-delimited role list, as some IdPs encode `roles` or `scope`.
+fn role_strings(value: &Value) -> Vec<String> {
+    match value {
+        Value::String(s) => s.split_whitespace().map(String::from).collect(),
+        value => claim_strings(value),
+    }
+}
+
+/// Flattens a claim into its string values. Scalars produce one value, arrays produce one per scalar element.
+pub fn claim_strings(value: &Value) -> Vec<String> {
+    match value {
+        Value::Null | Value::Object(_) => vec![],
+        Value::Bool(b) => vec![b.to_string()],
+        Value::Number(n) => vec![n.to_string()],
+        Value::String(s) => vec![s.clone()],
+        Value::Array(items) => items
+            .iter()
+            .filter(|x| !x.is_array())
+            .flat_map(claim_strings)
+            .collect(),
+    }
+}
+
+/// A claim as one header value: scalars as they are, arrays of scalars joined by `separator`, objects as JSON.
+/// `None` for null, an empty array, or nothing left after `sanitize_header`.
+pub fn header_value(value: &Value, separator: &str, sanitize: HeaderSanitize) -> Option<String> {
+    let value = match value {
+        Value::Null => return None,
+        Value::Object(_) => value.to_string(),
+        Value::Array(_) => {
+            let items = claim_strings(value);
+            if items.is_empty() {
+                return None;
+            }
+            items.join(separator)
+        }
+        _ => claim_strings(value).pop()?,
+    };
+    sanitize_header(&value, sanitize)
+}
+
+/// Neutralizes control characters, CR and LF above all, so an IdP-supplied value can't split headers. `None` if nothing is left.
+/// Idempotent, so values sanitized when the session was minted pass through unchanged when emitted.
+pub fn sanitize_header(value: &str, sanitize: HeaderSanitize) -> Option<String> {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        if !c.is_control() {
+            out.push(c);
+        } else if sanitize == HeaderSanitize::PercentEncode {
+            let mut buf = [0; 4];
+            for byte in c.encode_utf8(&mut buf).bytes() {
+                out.push_str(&format!("%{byte:02X}"));
+            }
+        }
+    }
+    Some(out).filter(|x| !x.is_empty())
+}
+
+/// Captures the values of the named top-level claims from the decoded token.
+pub fn claim_values<'a>(
+    raw: &Value,
+    names: impl IntoIterator<Item = &'a str>,
+) -> HashMap<String, Vec<String>> {
+    let mut out = HashMap::new();
+    for name in names {
+        if let Some(value) = raw.get(name) {
+            let values = claim_strings(value);
+            if !values.is_empty() {
+                out.insert(name.to_string(), values);
+            }
+        }
+    }
+    out
+}
+
+/// Copies the allowlisted top-level claims, for forwarding the claim bag as a whole.
+pub fn allowed_claims(raw: &Value, allowlist: &[String]) -> Map<String, Value> {
+    allowlist
+        .iter()
+        .filter_map(|name| Some((name.clone(), raw.get(name)?.clone())))
+        .collect()
+}
+
+/// Every role in `all_of`, and at least one role of each non-empty group in `any_of`.
+pub fn roles_satisfy(roles: &[String], all_of: &[&str], any_of: &[Vec<&str>]) -> bool {
+    let has = |role: &&str| roles.iter().any(|x| x == role);
+    all_of.iter().all(has)
+        && any_of
+            .iter()
+            .all(|group| group.is_empty() || group.iter().any(has))
+}
+
+/// Scopes granted to a session: the `scope` claim if the token carries one, else the token response's `scope`.
+/// The token endpoint may omit `scope` when it granted exactly what was requested, so that is the last resort.
+pub fn granted_scopes(raw: &Value, response: Option<&str>, requested: &str) -> Vec<String> {
+    match raw.get("scope") {
+        Some(value) => role_strings(value),
+        None => response
+            .unwrap_or(requested)
+            .split_whitespace()
+            .map(String::from)
+            .collect(),
+    }
+}
+
+/// A claim satisfies a requirement if any of its values is allowed. For array claims such as `aud`, that means any element.
+pub fn any_allowed(values: &[String], allowed: &[String]) -> bool {
+    values.iter().any(|x| allowed.contains(x))
+}
+
+/// Roles for a refreshed session. Some IdPs omit roles from refreshed id_tokens, so an empty result can optionally keep the prior roles.
+pub fn refreshed_roles(
+    prior: Vec<String>,
+    refreshed: Vec<String>,
+    keep_prior: bool,
+) -> Vec<String> {
+    if refreshed.is_empty() && !prior.is_empty() {
+        if keep_prior {
+            warn!("refreshed id_token has no roles, keeping the prior roles");
+            return prior;
+        }
+        warn!("refreshed id_token has no roles, the session loses its roles (see keep_roles_on_refresh)");
+    }
+    refreshed
+}
+
+/// Canonical form of an email address: trimmed, lowercased, without a trailing dot on the domain.
+/// Safe for `email` specifically since providers treat addresses case-insensitively in practice; not a general claim transform.
+pub fn normalize_email(email: &str) -> String {
+    email.trim().trim_end_matches('.').to_lowercase()
+}
+
+/// Applies `normalize_email` to the `email` entry of a claim set, if present.
+pub fn normalize_email_claim(claims: &mut Map<String, Value>) {
+    if let Some(Value::String(email)) = claims.get_mut("email") {
+        *email = normalize_email(email);
+    }
+}
+
+enum TemplatePiece<'a> {
+    Literal(&'a str),
+    Claim(&'a str),
+}
+
+/// Splits a `header_claims` value into literals and `{claim}` placeholders. A value without placeholders names a single claim.
+fn template_pieces(template: &str) -> Vec<TemplatePiece<'_>> {
+    let mut pieces = vec![];
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+        pieces.push(TemplatePiece::Literal(&rest[..start]));
+        pieces.push(TemplatePiece::Claim(&rest[start + 1..start + len]));
+        rest = &rest[start + len + 1..];
+    }
+    if pieces.is_empty() {
+        return vec![TemplatePiece::Claim(template)];
+    }
+    pieces.push(TemplatePiece::Literal(rest));
+    pieces
+}
+
+/// Claim names a `header_claims` value refers to, so they can be captured into the session.
+pub fn template_claims(template: &str) -> Vec<&str> {
+    template_pieces(template)
+        .into_iter()
+        .filter_map(|x| match x {
+            TemplatePiece::Claim(name) => Some(name),
+            TemplatePiece::Literal(_) => None,
+        })
+        .collect()
+}
+
+/// Renders a `header_claims` value. `None` if the header should be left out: always when a plain claim name is missing,
+/// and for a missing placeholder unless `missing` is `Empty`.
+pub fn render_template<'a>(
+    template: &str,
+    lookup: impl Fn(&str) -> Option<&'a str>,
+    missing: MissingClaimPolicy,
+) -> Option<String> {
+    let pieces = template_pieces(template);
+    let plain = pieces.len() == 1;
+    let mut out = String::new();
+    for piece in pieces {
+        match piece {
+            TemplatePiece::Literal(x) => out.push_str(x),
+            TemplatePiece::Claim(name) => match lookup(name) {
+                Some(value) => out.push_str(value),
+                None if plain || missing == MissingClaimPolicy::Omit => return None,
+                None => (),
+            },
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_header_value() {
+        assert_eq!(header_value(&json!(null), ",", HeaderSanitize::Strip), None);
+        assert_eq!(
+            header_value(&json!(42), ",", HeaderSanitize::Strip).as_deref(),
+            Some("42")
+        );
+        assert_eq!(
+            header_value(
+                &json!(["admin", "dev", 3, {"x": 1}]),
+                ", ",
+                HeaderSanitize::Strip
+            )
+            .as_deref(),
+            Some("admin, dev, 3")
+        );
+        assert_eq!(header_value(&json!([]), ",", HeaderSanitize::Strip), None);
+        assert_eq!(
+            header_value(&json!({"city": "Oslo"}), ",", HeaderSanitize::Strip).as_deref(),
+            Some(r#"{"city":"Oslo"}"#)
+        );
+        assert_eq!(
+            header_value(
+                &json!("evil\r\nSet-Cookie: x=1"),
+                ",",
+                HeaderSanitize::Strip
+            )
+            .as_deref(),
+            Some("evilSet-Cookie: x=1")
+        );
+    }
+
+    #[test]
+    fn test_sanitize_header() {
+        let evil = "a\r\nSet-Cookie: x=1";
+        assert_eq!(
+            sanitize_header(evil, HeaderSanitize::Strip).as_deref(),
+            Some("aSet-Cookie: x=1")
+        );
+        let encoded = sanitize_header(evil, HeaderSanitize::PercentEncode).unwrap();
+        assert_eq!(encoded, "a%0D%0ASet-Cookie: x=1");
+        assert_eq!(
+            sanitize_header(&encoded, HeaderSanitize::PercentEncode).as_deref(),
+            Some(&*encoded)
+        );
+        assert_eq!(sanitize_header("\r\n", HeaderSanitize::Strip), None);
+    }
+
+    #[test]
+    fn test_header_templates() {
+        let values = HashMap::from([("given_name", "Ada"), ("family_name", "Lovelace")]);
+        let lookup = |x: &str| values.get(x).copied();
+
+        assert_eq!(template_claims("email"), vec!["email"]);
+        assert_eq!(
+            template_claims("{given_name} {family_name}"),
+            vec!["given_name", "family_name"]
+        );
+        assert_eq!(
+            render_template("given_name", lookup, MissingClaimPolicy::Empty).as_deref(),
+            Some("Ada")
+        );
+        assert_eq!(
+            render_template("email", lookup, MissingClaimPolicy::Empty),
+            None
+        );
+        assert_eq!(
+            render_template(
+                "{given_name} {family_name}",
+                lookup,
+                MissingClaimPolicy::Omit
+            )
+            .as_deref(),
+            Some("Ada Lovelace")
+        );
+        assert_eq!(
+            render_template("<{email}>", lookup, MissingClaimPolicy::Omit),
+            None
+        );
+        assert_eq!(
+            render_template("<{email}> {given_name", lookup, MissingClaimPolicy::Empty).as_deref(),
+            Some("<> {given_name")
+        );
+    }
+
+    #[test]
+    fn test_array_claims() {
+        let raw = json!({
+            "aud": ["account", "my_client"],
+            "email_verified": true,
+            "address": { "country": "US" },
+        });
+        let values = claim_values(&raw, ["aud", "email_verified", "address", "missing"]);
+        assert_eq!(values["aud"], vec!["account", "my_client"]);
+        assert_eq!(values["email_verified"], vec!["true"]);
+        assert!(!values.contains_key("address"));
+        assert!(!values.contains_key("missing"));
+
+        assert!(any_allowed(&values["aud"], &["my_client".to_string()]));
+        assert!(!any_allowed(&values["aud"], &["other".to_string()]));
+    }
+
+    #[test]
+    fn test_resource_roles() {
+        // trimmed from a Keycloak access token
+        let keycloak = json!({
+            "exp": 1700000000,
+            "iss": "https://idp.my.domain/realms/home",
+            "aud": ["grafana.my.domain", "account"],
+            "sub": "1b2b3c4d-0000-4000-8000-000000000000",
+            "typ": "Bearer",
+            "azp": "grafana.my.domain",
+            "realm_access": { "roles": ["offline_access", "default-roles-home"] },
+            "resource_access": {
+                "grafana.my.domain": { "roles": ["editor"] },
+                "account": { "roles": ["manage-account", "view-profile"] }
+            },
+            "preferred_username": "jane",
+        });
+        let realm = extract_roles(
+            &keycloak,
+            &["realm_access.roles".to_string()],
+            RolesClaimsMode::First,
+        );
+        let roles = merge_roles(realm, resource_roles(&keycloak, "grafana.my.domain"));
+        assert_eq!(
+            roles,
+            vec!["offline_access", "default-roles-home", "editor"]
+        );
+        assert!(resource_roles(&keycloak, "missing").is_empty());
+    }
+
+    #[test]
+    fn test_roles_satisfy() {
+        let roles = ["staff", "editor"].map(String::from);
+        assert!(roles_satisfy(&roles, &[], &[]));
+        assert!(roles_satisfy(&roles, &["staff"], &[]));
+        assert!(!roles_satisfy(&roles, &["staff", "admin"], &[]));
+        assert!(roles_satisfy(&roles, &[], &[vec!["admin", "editor"]]));
+        assert!(!roles_satisfy(&roles, &[], &[vec!["admin", "owner"]]));
+        assert!(roles_satisfy(
+            &roles,
+            &["staff"],
+            &[vec!["admin", "editor"]]
+        ));
+        assert!(!roles_satisfy(&roles, &["admin"], &[vec!["editor"]]));
+        // every group needs one of its own roles
+        assert!(roles_satisfy(
+            &roles,
+            &[],
+            &[vec!["staff"], vec!["editor", "owner"]]
+        ));
+        assert!(!roles_satisfy(&roles, &[], &[vec!["staff"], vec!["admin"]]));
+    }
+
+    #[test]
+    fn test_granted_scopes() {
+        let token = json!({ "scope": "openid api:read" });
+        assert_eq!(
+            granted_scopes(&token, Some("openid"), "openid"),
+            vec!["openid", "api:read"]
+        );
+        let token = json!({ "sub": "a" });
+        assert_eq!(
+            granted_scopes(&token, Some("openid api:write"), "openid"),
+            vec!["openid", "api:write"]
+        );
+        assert_eq!(
+            granted_scopes(&token, None, "openid email"),
+            vec!["openid", "email"]
+        );
+    }
+
+    #[test]
+    fn test_refreshed_roles() {
+        let paths = ["realm_access.roles".to_string()];
+        let login = json!({ "realm_access": { "roles": ["admin"] }, "sub": "a" });
+        // an IdP that drops claims from refreshed id_tokens
+        let refreshed = json!({ "sub": "a" });
+
+        let prior = extract_roles(&login, &paths, RolesClaimsMode::First);
+        let roles = extract_roles(&refreshed, &paths, RolesClaimsMode::First);
+        assert_eq!(
+            refreshed_roles(prior.clone(), roles.clone(), true),
+            vec!["admin"]
+        );
+        assert!(refreshed_roles(prior.clone(), roles, false).is_empty());
+
+        let changed = vec!["user".to_string()];
+        assert_eq!(refreshed_roles(prior, changed, true), vec!["user"]);
+    }
+
+    #[test]
+    fn test_normalize_email() {
+        assert_eq!(
+            normalize_email(" Jane.Doe@Example.COM. "),
+            "jane.doe@example.com"
+        );
+        assert_eq!(normalize_email("a@b.c"), "a@b.c");
+
+        let mut claims = json!({ "email": "A@B.C.", "name": "A B" });
+        normalize_email_claim(claims.as_object_mut().unwrap());
+        assert_eq!(claims, json!({ "email": "a@b.c", "name": "A B" }));
+    }
+
+    #[test]
+    fn test_roles_fallback() {
+        let paths = ["realm_access.roles", "groups", "roles"].map(String::from);
+        let keycloak = json!({ "realm_access": { "roles": ["admin", "user"] }, "groups": ["ops"] });
+        let federated = json!({ "realm_access": { "roles": [] }, "groups": ["ops", "dev"] });
+        let plain = json!({ "roles": "viewer" });
+        let delimited = json!({ "cognito:groups": "viewer  editor" });
+
+        assert_eq!(
+            extract_roles(&keycloak, &paths, RolesClaimsMode::First),
+            vec!["admin", "user"]
+        );
+        assert_eq!(
+            extract_roles(&federated, &paths, RolesClaimsMode::First),
+            vec!["ops", "dev"]
+        );
+        assert_eq!(
+            extract_roles(&plain, &paths, RolesClaimsMode::First),
+            vec!["viewer"]
+        );
+        assert_eq!(
+            extract_roles(
+                &delimited,
+                &["cognito:groups".to_string()],
+                RolesClaimsMode::First
+            ),
+            vec!["viewer", "editor"]
+        );
+        assert_eq!(
+            extract_roles(&keycloak, &paths, RolesClaimsMode::Merge),
+            vec!["admin", "user", "ops"]
+        );
+        assert!(extract_roles(&json!({}), &paths, RolesClaimsMode::Merge).is_empty());
+    }
+}
@@ -1,129 +1,1101 @@
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
     net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
+use anyhow::{bail, Context};
+use arc_swap::ArcSwap;
 use cidr::IpCidr;
-use hmac::{Hmac, Mac};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use serde_with::{serde_as, DisplayFromStr};
-use sha2::Sha256;
+use serde_with::{serde_as, DisplayFromStr, OneOrMany};
+use tracing::{error, info, warn};
+
+use crate::{
+    claims::{extract_roles, merge_roles, resource_roles},
+    jwt::SessionKeys,
+    jwtc,
+    metrics::{CONFIG_GENERATION, CONFIG_RELOADS},
+    response::status_code,
+};
 use url::Url;
 
+#[serde_as]
 #[derive(Serialize, Deserialize)]
 pub struct Config {
     pub bind: SocketAddr,
+    /// On SIGTERM or SIGINT, in-flight requests get this long to finish before the process exits anyway.
+    #[serde(default = "default_shutdown_timeout_sec")]
+    pub shutdown_timeout_sec: u64,
     pub prometheus_bind: Option<SocketAddr>,
     pub public: Url,
+    /// Path the routes are served at, when a proxy strips a prefix of `public` before forwarding. Defaults to the path of `public`.
+    pub route_base: Option<String>,
+    /// Development only: permit an `http://` public url, which produces an insecure OIDC redirect_uri.
+    #[serde(default)]
+    pub allow_insecure_public: bool,
     pub client_id: String,
+    /// May instead come from `client_secret_file` or `OIPLEASE_CLIENT_SECRET`, in that order.
+    #[serde(default)]
     pub client_secret: String,
+    pub client_secret_file: Option<PathBuf>,
     pub issuer: Url,
+    /// Refuse to create sessions from id_tokens with a missing or empty `sub`.
+    #[serde(default = "default_true")]
+    pub require_sub: bool,
+    /// Audiences an id_token's `aud` must include besides the client id, for providers that issue tokens to several audiences at once.
+    #[serde(default)]
+    pub expected_audiences: Vec<String>,
+    /// Reject `/auth` callbacks without the RFC 9207 `iss` parameter. A present `iss` is always checked against `issuer`.
+    #[serde(default)]
+    pub require_callback_issuer: bool,
     #[serde(default = "default_refresh_time_sec")]
     pub oidc_refresh_time_sec: u64,
+    /// Randomizes each refresh deadline by up to this percentage either way, so replicas don't rediscover in lockstep.
+    #[serde(default = "default_oidc_refresh_jitter_percent")]
+    pub oidc_refresh_jitter_percent: f64,
+    /// Failed discoveries are retried with exponential backoff from `oidc_retry_base_sec` up to `oidc_retry_max_sec`, randomized by up to half.
+    #[serde(default = "default_oidc_retry_base_sec")]
+    pub oidc_retry_base_sec: f64,
+    #[serde(default = "default_oidc_retry_max_sec")]
+    pub oidc_retry_max_sec: f64,
+    /// Log a failed discovery once every this many attempts.
+    #[serde(default = "default_oidc_retry_warn_every")]
+    pub oidc_retry_warn_every: u32,
+    /// Directory to cache each provider's discovery document and JWKS in. At startup a cached copy younger than
+    /// `discovery_cache_max_age_sec` is used right away while a fresh discovery runs in the background.
+    /// Should the provider stay unreachable until the copy is that old, logins and renewals fail instead of trusting it further.
+    pub discovery_cache_dir: Option<PathBuf>,
+    #[serde(default = "default_discovery_cache_max_age_sec")]
+    pub discovery_cache_max_age_sec: i64,
+    /// `/health/ready` fails once any provider's last successful discovery is older than this. Defaults to three `oidc_refresh_time_sec`.
+    pub ready_max_discovery_age_sec: Option<i64>,
     #[serde(default = "default_scopes")]
     pub scopes: String,
+    /// Reject id_tokens whose `at_hash`/`c_hash` claims don't match the access token and code received alongside them.
+    #[serde(default)]
+    pub require_token_hash: bool,
+    /// OAuth `response_type` sent in the authorization request. Must include `code`; implicit `token` responses are rejected.
+    #[serde(default = "default_response_type")]
+    pub response_type: String,
+    /// May instead come from `jwt_key_file` or `OIPLEASE_JWT_KEY`, in that order.
+    #[serde(default)]
     pub jwt_key: String,
+    pub jwt_key_file: Option<PathBuf>,
+    /// Named HS256 session keys for rotation. The `primary` key signs, every key verifies.
+    /// Sessions without a `kid` are still verified with `jwt_key`, unless `jwt_key_retired`.
+    #[serde(default)]
+    pub jwt_keys: Vec<JwtKeyConfig>,
+    /// Stop verifying kid-less sessions with `jwt_key` once `jwt_keys` has taken over, so a leaked `jwt_key` can't mint sessions.
+    /// `jwt_key` still signs the login flow cookie.
+    #[serde(default)]
+    pub jwt_key_retired: bool,
+    /// Session JWT signature. `jwt_key` remains in use for login flow state either way.
+    #[serde(default)]
+    pub jwt_algorithm: JwtAlgorithm,
+    /// PEM private key for `RS256`/`ES256`. Instances without it can verify sessions but not mint them.
+    pub jwt_private_key_file: Option<PathBuf>,
+    /// PEM public key for `RS256`/`ES256`. Derived from the private key if omitted.
+    pub jwt_public_key_file: Option<PathBuf>,
+    #[serde(default = "default_jwt_key_min_length")]
+    pub jwt_key_min_length: usize,
+    /// Start despite a short or guessable `jwt_key`. Anyone who guesses the key can mint sessions, so only use this temporarily.
+    #[serde(default)]
+    pub allow_weak_jwt_key: bool,
+    /// Ordered dot-paths into the id_token claims to read roles from, e.g. `groups` or `cognito:groups`.
+    /// A single path may be given as `roles_claim_path`. Claims may be arrays or space-delimited strings.
+    #[serde_as(as = "OneOrMany<_>")]
+    #[serde(default = "default_roles_claims", alias = "roles_claim_path")]
+    pub roles_claims: Vec<String>,
+    #[serde(default)]
+    pub roles_claims_mode: RolesClaimsMode,
+    /// Also merge this client's Keycloak roles from `resource_access.<roles_client_id>.roles`.
+    pub roles_client_id: Option<String>,
     pub cookie_name: String,
-    pub success_header: String,
+    /// Headers set to `true` on successful validation. Accepts a single name (the legacy `success_header`) or a list, e.g. to emit old and new names during a migration.
+    #[serde_as(as = "OneOrMany<_>")]
+    #[serde(alias = "success_header")]
+    pub success_headers: Vec<String>,
     #[serde(default = "default_login_renew_seconds")]
     pub login_renew_seconds: i64,
+    /// Also renew a session whose access token expires within this many seconds, regardless of `login_renew_seconds`,
+    /// so the refresh token keeps being exercised on endpoints that see little traffic.
+    pub eager_refresh_seconds: Option<i64>,
     #[serde(default = "default_login_cache_minutes")]
     pub login_cache_minutes: i64,
+    /// Hard cap on a session's life since the user logged in, however often it's renewed in between.
+    pub absolute_session_seconds: Option<i64>,
+    /// Leeway for clock drift between replicas: sessions expire this much later, and renew this much earlier.
+    #[serde(default = "default_clock_skew_seconds")]
+    pub clock_skew_seconds: i64,
+    /// Callbacks to `/auth` whose login flow is older than this are sent back through `/login` instead of attempting a likely-expired code exchange.
+    pub max_auth_flow_age_sec: Option<i64>,
     #[serde(default)]
     pub refresh_tokens: bool,
+    /// Keep the session's roles when a refreshed id_token carries none, for IdPs that omit claims on refresh.
+    #[serde(default)]
+    pub keep_roles_on_refresh: bool,
+    /// A session is refreshed at most once per this many seconds, even if it keeps landing in the renew window.
+    #[serde(default = "default_min_refresh_interval_sec")]
+    pub min_refresh_interval_sec: i64,
+    /// Re-derive roles from the userinfo endpoint this often, even without a refresh token.
+    /// Keeps the access token in the session. On fetch errors the cached roles are used.
+    pub role_refresh_sec: Option<i64>,
+    /// Keep the id_token in the session so logout can send it as `id_token_hint`, at the cost of a larger cookie.
+    #[serde(default)]
+    pub logout_with_hint: bool,
     /// If true, when the access token expires, so does the login JWT.
     #[serde(default)]
     pub honor_token_expiry: bool,
-    #[serde(default = "default_true")]
-    pub cookie_secure: bool,
+    /// If true, a present but unverifiable session cookie gets a 401 (and thus a fresh login) instead of a 400.
+    #[serde(default)]
+    pub relogin_on_invalid_cookie: bool,
+    /// Where `/auth` sends users whose fresh login can't satisfy the target url's required roles or claims, instead of minting a session that `/validate` would 403.
+    /// The target is passed along as the `url` query parameter.
+    pub access_denied_url: Option<Url>,
+    /// What `/auth` does when the provider sends the user back with an `error`, such as `access_denied`.
+    #[serde(default)]
+    pub callback_error: CallbackErrorMode,
+    /// If true, `/login` redirects straight back when the request already carries a session sufficient for the target url.
+    #[serde(default)]
+    pub login_reuse_session: bool,
+    /// `auto` sets Secure only when the proxy forwarded the request with `x-forwarded-proto: https`.
+    #[serde(default)]
+    pub cookie_secure: CookieSecure,
+    /// Leave empty for a host-only cookie, as required by a `__Host-` prefixed `cookie_name`.
+    #[serde(default)]
     pub cookie_domain: String,
+    /// Scopes the session cookie to this path prefix. Must cover every protected route, or `/validate` sees no cookie.
+    #[serde(default = "default_cookie_path")]
+    pub cookie_path: String,
+    #[serde(default)]
+    pub cookie_same_site: CookieSameSite,
+    /// Encrypt the session cookie (XChaCha20-Poly1305, keyed from `jwt_key`) so the tokens inside aren't readable from the cookie.
+    /// Adds about 54 bytes. Plain cookies issued before enabling this are still accepted until they expire.
+    #[serde(default)]
+    pub cookie_encrypt: bool,
+    /// Compression of the session cookie. Cookies written with another algorithm still decode, so this can be changed without logging users out.
+    #[serde(default)]
+    pub cookie_compression: jwtc::Algorithm,
+    /// Session cookies larger than this are split into `<cookie_name>.0`, `.1`, ..., since browsers drop cookies over about 4KB.
+    #[serde(default = "default_cookie_max_size")]
+    pub cookie_max_size: usize,
+    /// Upper bound on the encoded session cookie size. Sessions larger than this fail instead of being handed to a browser that will drop them.
+    #[serde(default = "default_max_total_cookie_bytes")]
+    pub max_total_cookie_bytes: usize,
 
     #[serde(default)]
     pub required_roles: Vec<String>,
+    /// If non-empty, the user must also hold at least one of these roles.
+    #[serde(default)]
+    pub any_of_roles: Vec<String>,
+    /// OAuth scopes the session must have been granted, all of them.
+    #[serde(default)]
+    pub required_scopes: Vec<String>,
+    /// Maps claim names to allowed values. Every listed claim must hold at least one allowed value; array claims match if any element does.
+    #[serde(default)]
+    pub required_claims: HashMap<String, Vec<String>>,
+    /// Maps header names to a claim name, or to a template such as `{given_name} {family_name}`.
     #[serde(default)]
     pub header_claims: HashMap<String, String>,
+    /// What a `header_claims` template does when a placeholder's claim is missing.
+    #[serde(default)]
+    pub header_claims_missing: MissingClaimPolicy,
+    /// Joins the elements of array claims such as `groups` in `header_claims`. Object claims are sent as JSON, or pick a field with a dot path.
+    #[serde(default = "default_header_claims_separator")]
+    pub header_claims_separator: String,
+    /// How control characters in claim-derived header values are neutralized.
+    #[serde(default)]
+    pub header_sanitize: HeaderSanitize,
+    /// Trim, lowercase, and drop a trailing dot from the `email` claim before forwarding it, so downstreams keying on email see one form.
+    #[serde(default)]
+    pub normalize_email: bool,
+    /// Header carrying the allowlisted id_token claims as base64-encoded JSON.
+    pub userinfo_header: Option<String>,
+    /// Claims included in `userinfo_header`. Claims not listed are never forwarded.
+    #[serde(default)]
+    pub userinfo_header_claims: Vec<String>,
+    /// Header carrying the session's resolved roles, e.g. `X-Auth-Roles`.
+    pub roles_header: Option<String>,
+    #[serde(default)]
+    pub roles_header_mode: RolesHeaderMode,
+    #[serde(default = "default_roles_header_delimiter")]
+    pub roles_header_delimiter: String,
+    /// If non-empty, only these roles are forwarded in `roles_header`.
+    #[serde(default)]
+    pub roles_header_allowlist: Vec<String>,
+    /// Leave out `roles_header` entirely when no roles remain, instead of sending it empty.
+    #[serde(default)]
+    pub roles_header_omit_empty: bool,
+    /// Header carrying the access token expiry (unix seconds). Only emitted when the session retains its access token.
+    pub access_token_expires_header: Option<String>,
+    /// Keep the access token in the session and pass it upstream in `access_token_header`, for backends calling APIs on the user's behalf.
+    #[serde(default)]
+    pub forward_access_token: bool,
+    /// Sent as `Bearer <token>` if this is `Authorization`, otherwise as the bare token.
+    #[serde(default = "default_access_token_header")]
+    pub access_token_header: String,
+    /// Emit an `X-Auth-Debug` header from `/validate` explaining each decision. Exposes roles and config, never enable in production.
+    #[serde(default)]
+    pub debug_decisions: bool,
+    /// Clients allowed to receive `X-Auth-Debug`. Required with `debug_decisions`.
+    #[serde(default)]
+    pub debug_decisions_cidr: Vec<IpCidr>,
+    /// Status of a successful `/validate` response, including bypassed and public paths. Must be a 2xx.
+    #[serde(default = "default_validate_success_status")]
+    pub validate_success_status: u16,
+    /// Static body of a successful `/validate` response, empty by default.
+    pub validate_success_body: Option<String>,
+    /// Answer unauthenticated `/validate` requests from browsers (`Accept` preferring `text/html`) with a 302 to `/login` instead of a 401.
+    /// Traefik's forwardAuth relays the redirect; NGINX `auth_request` only understands 2xx/401/403, use `auth-signin` there instead.
+    #[serde(default)]
+    pub validate_redirect_to_login: bool,
+    /// Keep an in-memory denylist of logged-out sessions, so their cookies stop working before they expire.
+    /// Per instance only: with several replicas, a revocation only takes effect on the replica that handled it.
+    #[serde(default)]
+    pub session_revocation: bool,
+    /// Roles whose holders may `POST /revoke?sub=<sub>` to revoke all of a user's sessions. The endpoint is disabled if empty.
+    #[serde(default)]
+    pub revoke_required_roles: Vec<String>,
+    /// Body of `/validate` 401s. Every 401 carries `WWW-Authenticate: Bearer realm="<www_authenticate_realm>"`.
+    #[serde(default)]
+    pub validate_error_format: ValidateErrorFormat,
+    #[serde(default = "default_www_authenticate_realm")]
+    pub www_authenticate_realm: String,
+    /// Include the failure reason (e.g. "expired token") in `/validate` 401 bodies. Off by default to avoid leaking internals.
+    #[serde(default)]
+    pub verbose_errors: bool,
+    /// Total request header bytes `/validate` will process before answering 431.
+    #[serde(default = "default_max_header_bytes")]
+    pub max_header_bytes: usize,
+    /// `Cookie` header bytes `/validate` will process before answering 431.
+    #[serde(default = "default_max_cookie_header_bytes")]
+    pub max_cookie_header_bytes: usize,
+    /// Which proxy headers `/validate` reads the authorized url from.
+    #[serde(default)]
+    pub forwarded_headers_mode: ForwardedHeadersMode,
+    /// What `/validate` does when the request already carries one of the headers it emits, e.g. a client trying to spoof `success_headers`.
+    #[serde(default)]
+    pub conflicting_header_policy: ConflictingHeaderPolicy,
+    /// `Cache-Control` overrides keyed by route (e.g. `/login`). Everything else is sent `no-store`.
+    #[serde(default)]
+    pub cache_control: HashMap<String, String>,
+    /// Where `/login`, `/auth` and `/logout` may redirect to. Entries are hosts, optionally with a scheme (`https://app.my.domain`) or a leading `*.` for subdomains.
+    /// Without a scheme, http and https are both allowed.
+    #[serde(default)]
+    pub redirect_allowlist: Vec<String>,
+    /// What an empty `redirect_allowlist` allows.
+    #[serde(default)]
+    pub redirect_allowlist_default: RedirectAllowlistDefault,
+    /// Paths `/validate` passes immediately, before any cookie parsing or customization matching. A trailing `*` matches by prefix, e.g. `/static/*`.
+    #[serde(default)]
+    pub public_paths: Vec<String>,
+    /// Further identity providers, each serving the hosts it matches (checked in name order). Other hosts use the top-level `issuer`.
+    #[serde(default)]
+    pub providers: BTreeMap<String, ProviderConfig>,
     #[serde(default)]
     pub customizations: Vec<Customization>,
+    #[serde(default)]
+    pub customization_strategy: CustomizationStrategy,
     pub opentelemetry: Option<OtelConfig>,
+    /// CORS for every route. Permissive by default: `/revoke`, the only route exercising the session's privileges, checks `Origin` itself.
+    #[serde(default)]
+    pub cors: CorsConfig,
+    /// `OIPLEASE_LOG_FORMAT` overrides this, and also applies to messages logged while the config itself loads.
+    #[serde(default)]
+    pub log_format: LogFormat,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum CookieSameSite {
+    Strict,
+    #[default]
+    Lax,
+    /// Needed for embedded/iframe use. Requires `cookie_secure`.
+    None,
+}
+
+impl From<CookieSameSite> for cookie::SameSite {
+    fn from(value: CookieSameSite) -> Self {
+        match value {
+            CookieSameSite::Strict => cookie::SameSite::Strict,
+            CookieSameSite::Lax => cookie::SameSite::Lax,
+            CookieSameSite::None => cookie::SameSite::None,
+        }
+    }
+}
+
+/// `true`, `false` or `auto`.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(untagged)]
+pub enum CookieSecure {
+    Fixed(bool),
+    Detect(AutoDetect),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AutoDetect {
+    Auto,
+}
+
+impl Default for CookieSecure {
+    fn default() -> Self {
+        CookieSecure::Fixed(true)
+    }
+}
+
+impl CookieSecure {
+    /// Whether cookies set in reply to a request forwarded with `forwarded_proto` are Secure.
+    pub fn resolve(self, forwarded_proto: Option<&str>) -> bool {
+        match self {
+            CookieSecure::Fixed(secure) => secure,
+            CookieSecure::Detect(AutoDetect::Auto) => forwarded_proto
+                .and_then(|x| x.split(',').next())
+                .is_some_and(|x| x.trim().eq_ignore_ascii_case("https")),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct JwtKeyConfig {
+    pub kid: String,
+    pub key: String,
+    #[serde(default)]
+    pub primary: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+pub enum JwtAlgorithm {
+    #[default]
+    HS256,
+    RS256,
+    ES256,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RolesClaimsMode {
+    /// Use the first roles claim path that yields any roles.
+    #[default]
+    First,
+    /// Union the roles from every roles claim path.
+    Merge,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingClaimPolicy {
+    /// Leave the header out, as a plain claim name always does.
+    #[default]
+    Omit,
+    /// Substitute an empty string for the placeholder.
+    Empty,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderSanitize {
+    #[default]
+    Strip,
+    /// Replace each control character with its `%XX` encoding, keeping the value's length and position information.
+    PercentEncode,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RedirectAllowlistDefault {
+    /// Any http(s) url, the historical behavior.
+    #[default]
+    AllowAll,
+    /// Only urls with the same scheme, host and port as `public`.
+    SameOrigin,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardedHeadersMode {
+    /// `x-original-url`, as sent by NGINX `auth_request`.
+    Nginx,
+    /// `x-forwarded-proto`, `x-forwarded-host` and `x-forwarded-uri`, as sent by Traefik ForwardAuth.
+    Traefik,
+    /// `x-original-url` if present, otherwise the `x-forwarded-*` headers.
+    #[default]
+    Auto,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RolesHeaderMode {
+    /// One header, roles joined by `roles_header_delimiter`.
+    #[default]
+    Joined,
+    /// One header per role.
+    Repeated,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictingHeaderPolicy {
+    /// Our values always overwrite, nothing else happens.
+    Ignore,
+    /// Overwrite and log a warning naming the header.
+    #[default]
+    Log,
+    /// Refuse the request with a 400.
+    Reject,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per event, including the fields of the enclosing spans such as the request id.
+    Json,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum CallbackErrorMode {
+    /// A 401 with a short plain-text explanation.
+    #[default]
+    Page,
+    /// To `access_denied_url` if set, else back to the target url, with the provider's `error` and `error_description` appended.
+    /// The target url has no session, so the proxy sends the user to log in again, which loops if the IdP denies without prompting.
+    Redirect,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ValidateErrorFormat {
+    #[default]
+    Text,
+    /// `{"error":"unauthorized","reason":"..."}`, with `reason` only if `verbose_errors` is set.
+    Json,
+}
+
+/// How customizations combine when several match the same request.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum CustomizationStrategy {
+    /// Union the roles and claims of every match, bypassing if any match bypasses.
+    #[default]
+    Union,
+    /// Only the first matching customization, in config order, applies.
+    FirstMatch,
+    /// Only the match with the most specific path applies: exact `path`, then the longest `path_prefix`, then `path_regex`, then filters without a path.
+    /// Ties go to the earliest customization.
+    MostSpecific,
 }
 
 pub struct Customized<'a> {
     pub required_roles: Vec<&'a str>,
+    /// One group per source, the top-level config and each applied customization. A role is needed from every group.
+    pub any_of_roles: Vec<Vec<&'a str>>,
+    pub required_scopes: Vec<&'a str>,
+    pub required_claims: Vec<(&'a str, &'a [String])>,
     pub bypass: bool,
+    pub accept_any_issuer: bool,
+    pub client: Option<&'a ClientRegistration>,
+    pub deny_status: Option<u16>,
+    /// Indices into `customizations` of the rules that applied.
+    pub matched: Vec<usize>,
 }
 
 impl Config {
+    pub fn is_redirect_allowed(&self, url: &Url) -> bool {
+        if !matches!(url.scheme(), "http" | "https") {
+            return false;
+        }
+        if self.redirect_allowlist.is_empty() {
+            return match self.redirect_allowlist_default {
+                RedirectAllowlistDefault::AllowAll => true,
+                RedirectAllowlistDefault::SameOrigin => url.origin() == self.public.origin(),
+            };
+        }
+        let host = url.host_str().unwrap_or_default();
+        self.redirect_allowlist.iter().any(|entry| {
+            let (scheme, pattern) = match entry.split_once("://") {
+                Some((scheme, pattern)) => (Some(scheme), pattern),
+                None => (None, &**entry),
+            };
+            if scheme.is_some_and(|x| x != url.scheme()) {
+                return false;
+            }
+            match pattern.strip_prefix("*.") {
+                Some(domain) => host.strip_suffix(domain).is_some_and(|x| x.ends_with('.')),
+                None => host == pattern,
+            }
+        })
+    }
+
+    pub fn is_public_path(&self, path: &str) -> bool {
+        self.public_paths.iter().any(|x| match x.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == x,
+        })
+    }
+
+    /// Roles of a session, from decoded id_token or userinfo claims. Shared by login and every refresh path so roles stay consistent.
+    pub fn session_roles(&self, raw: &serde_json::Value) -> Vec<String> {
+        let roles = extract_roles(raw, &self.roles_claims, self.roles_claims_mode);
+        match &self.roles_client_id {
+            Some(client_id) => merge_roles(roles, resource_roles(raw, client_id)),
+            None => roles,
+        }
+    }
+
+    /// `__Host-` cookies must be Secure, have Path=/ and no Domain, or browsers reject them.
+    pub fn cookie_host_prefixed(&self) -> bool {
+        self.cookie_name.starts_with("__Host-")
+    }
+
+    /// Whether `address` gets the `X-Auth-Debug` decision trace.
+    pub fn debug_decisions_for(&self, address: IpAddr) -> bool {
+        self.debug_decisions
+            && self
+                .debug_decisions_cidr
+                .iter()
+                .any(|x| x.contains(&address))
+    }
+
+    /// Top-level fields outside `RELOADABLE` that differ from `running`, so a reload can point out what it didn't apply.
+    pub fn restart_required(&self, running: &Config) -> Vec<String> {
+        let (Ok(serde_json::Value::Object(new)), Ok(serde_json::Value::Object(old))) =
+            (serde_json::to_value(self), serde_json::to_value(running))
+        else {
+            return vec![];
+        };
+        new.iter()
+            .filter(|(name, _)| !RELOADABLE.contains(&name.as_str()))
+            .filter(|(name, value)| old.get(*name) != Some(value))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// `host` as a metric label if some customization names it, `other` otherwise, so labels stay bounded.
+    pub fn metrics_hostname(&self, host: &str) -> &str {
+        self.customizations
+            .iter()
+            .flat_map(|x| {
+                x.filter
+                    .hostname
+                    .iter()
+                    .chain(x.filter.hostnames.iter().flatten())
+            })
+            .find(|x| x.eq_ignore_ascii_case(host))
+            .map(|x| &**x)
+            .unwrap_or("other")
+    }
+
+    /// Names of the identity headers `/validate` emits on success, which only the trusted proxy may set.
+    /// Leaves out `access_token_header`: API clients legitimately send their own `Authorization`, which `/validate` overwrites anyway.
+    pub fn emitted_headers(&self) -> impl Iterator<Item = &str> {
+        self.success_headers
+            .iter()
+            .chain(self.header_claims.keys())
+            .chain(&self.userinfo_header)
+            .chain(&self.roles_header)
+            .chain(&self.access_token_expires_header)
+            .map(|x| &**x)
+    }
+
+    /// Parses the config file after substituting `${VAR}` references, then fills in secrets not given inline.
+    pub fn load(raw: &str, env: impl Fn(&str) -> Option<String>) -> anyhow::Result<Config> {
+        let raw = interpolate_env(raw, &env)?;
+        let mut config: Config = serde_yaml::from_str(&raw).context("failed to parse config")?;
+        resolve_secret(
+            "client_secret",
+            &mut config.client_secret,
+            config.client_secret_file.as_deref(),
+            env("OIPLEASE_CLIENT_SECRET"),
+        )?;
+        resolve_secret(
+            "jwt_key",
+            &mut config.jwt_key,
+            config.jwt_key_file.as_deref(),
+            env("OIPLEASE_JWT_KEY"),
+        )?;
+        Ok(config)
+    }
+
+    /// Checks for misconfigurations that would otherwise surface later as confusing runtime failures.
+    /// Returns every problem found rather than stopping at the first.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = vec![];
+        match self.public.scheme() {
+            "https" => (),
+            "http" if self.allow_insecure_public => {
+                warn!("`public` is not https, the OIDC redirect_uri is insecure. this must not be used in production");
+                if self.cookie_secure == CookieSecure::Fixed(true) {
+                    warn!("`cookie_secure` is set but `public` is http, browsers will not store the session cookie");
+                }
+            }
+            "http" => problems.push(
+                "`public` must be https (set `allow_insecure_public` to override for development)"
+                    .to_string(),
+            ),
+            scheme => problems.push(format!("`public` has unsupported scheme '{scheme}'")),
+        }
+        if self.public.query().is_some() || self.public.fragment().is_some() {
+            problems.push(format!(
+                "`public` '{}' must not have a query or fragment, routes such as /auth are appended to its path",
+                self.public
+            ));
+        }
+
+        if self.cookie_host_prefixed()
+            && (!self.cookie_domain.is_empty() || self.cookie_secure == CookieSecure::Fixed(false))
+        {
+            problems.push("a `__Host-` prefixed `cookie_name` requires an empty `cookie_domain` and `cookie_secure` other than `false`".to_string());
+        }
+        if !self.cookie_path.starts_with('/') {
+            problems.push(format!(
+                "`cookie_path` '{}' must start with /",
+                self.cookie_path
+            ));
+        } else if self.cookie_host_prefixed() && self.cookie_path != "/" {
+            problems
+                .push("a `__Host-` prefixed `cookie_name` requires `cookie_path: /`".to_string());
+        } else if !public_url_base(&self.public).starts_with(&path_base(&self.cookie_path)) {
+            warn!("`public` is outside `cookie_path`, so /logout and /validate-token never see the session cookie");
+        }
+        if self.cookie_domain.is_empty() && !self.cookie_host_prefixed() {
+            warn!("`cookie_domain` is empty, so the session cookie is host-only and only reaches `public`'s host");
+        }
+
+        if self.cookie_same_site == CookieSameSite::None
+            && self.cookie_secure != CookieSecure::Fixed(true)
+        {
+            problems.push("`cookie_same_site: none` requires `cookie_secure: true`, browsers reject SameSite=None cookies without Secure".to_string());
+        }
+
+        if self.jwt_key.is_empty() {
+            problems.push("`jwt_key` must not be empty".to_string());
+        } else if let Some(weakness) = jwt_key_weakness(&self.jwt_key, self.jwt_key_min_length) {
+            if self.allow_weak_jwt_key {
+                warn!("`jwt_key` {weakness}! sessions can be forged by anyone who guesses it. replace it as soon as possible");
+            } else {
+                problems.push(format!(
+                    "`jwt_key` {weakness} (set `allow_weak_jwt_key` to override)"
+                ));
+            }
+        }
+
+        if self.debug_decisions {
+            if self.debug_decisions_cidr.is_empty() {
+                problems.push("`debug_decisions` requires `debug_decisions_cidr`".to_string());
+            }
+            warn!("!!! `debug_decisions` is enabled: /validate exposes roles and customizations in X-Auth-Debug. DO NOT USE IN PRODUCTION !!!");
+        }
+        if self.forward_access_token && !self.cookie_encrypt {
+            warn!("`forward_access_token` is set without `cookie_encrypt`, the access token is readable from the session cookie");
+        }
+        if self.honor_token_expiry && !self.refresh_tokens {
+            warn!("`honor_token_expiry` is set without `refresh_tokens`, sessions end with the access token and every expiry is a full login");
+        }
+
+        if !self.jwt_keys.is_empty() {
+            let primaries = self.jwt_keys.iter().filter(|x| x.primary).count();
+            if primaries != 1 {
+                problems.push(format!(
+                    "exactly one of `jwt_keys` must be `primary`, found {primaries}"
+                ));
+            }
+            for (i, entry) in self.jwt_keys.iter().enumerate() {
+                if self.jwt_keys[..i].iter().any(|x| x.kid == entry.kid) {
+                    problems.push(format!("duplicate `jwt_keys` kid '{}'", entry.kid));
+                }
+                if let Some(weakness) = jwt_key_weakness(&entry.key, self.jwt_key_min_length) {
+                    if self.allow_weak_jwt_key {
+                        warn!("`jwt_keys` key '{}' {weakness}!", entry.kid);
+                    } else {
+                        problems.push(format!("`jwt_keys` key '{}' {weakness}", entry.kid));
+                    }
+                }
+            }
+        }
+        if self.jwt_key_retired && self.jwt_keys.is_empty() {
+            problems.push("`jwt_key_retired` requires `jwt_keys`".to_string());
+        }
+        if self.jwt_algorithm != JwtAlgorithm::HS256 {
+            if !self.jwt_keys.is_empty() {
+                problems.push(format!(
+                    "`jwt_keys` only applies to HS256, not `jwt_algorithm` {:?}",
+                    self.jwt_algorithm
+                ));
+            }
+            if let Err(e) = SessionKeys::load(self) {
+                problems.push(format!("{e:#}"));
+            }
+        }
+
+        if !(200..300).contains(&self.validate_success_status)
+            || status_code(self.validate_success_status).is_none()
+        {
+            problems.push(format!(
+                "`validate_success_status` {} is not a 2xx status code",
+                self.validate_success_status
+            ));
+        }
+
+        if self
+            .www_authenticate_realm
+            .chars()
+            .any(|x| x == '"' || x == '\\' || x.is_control())
+        {
+            problems.push("`www_authenticate_realm` must not contain quotes, backslashes or control characters".to_string());
+        }
+
+        let access_token_header = self
+            .forward_access_token
+            .then_some(&*self.access_token_header);
+        for header in self.emitted_headers().chain(access_token_header) {
+            if !is_header_name(header) {
+                problems.push(format!("'{header}' is not a valid header name"));
+            }
+        }
+
+        if self.cors.allow_credentials && (self.cors.any_origin() || self.cors.any_method()) {
+            problems.push("`cors.allow_credentials` requires explicit `cors.allow_origins` and `cors.allow_methods`, browsers reject credentials with `*`".to_string());
+        }
+        for origin in self.cors.allow_origins.iter().filter(|x| *x != "*") {
+            match Url::parse(origin) {
+                Ok(url) if url.origin().ascii_serialization() == origin.trim_end_matches('/') => (),
+                _ => problems.push(format!(
+                    "`cors.allow_origins` entry '{origin}' is not an origin like https://app.my.domain"
+                )),
+            }
+        }
+        if self.absolute_session_seconds.is_some_and(|x| x <= 0) {
+            problems.push("`absolute_session_seconds` must be positive".to_string());
+        }
+        if self.clock_skew_seconds < 0 {
+            problems.push("`clock_skew_seconds` must not be negative".to_string());
+        }
+        if !(0.0..100.0).contains(&self.oidc_refresh_jitter_percent) {
+            problems
+                .push("`oidc_refresh_jitter_percent` must be at least 0 and below 100".to_string());
+        }
+        if !(self.oidc_retry_base_sec > 0.0 && self.oidc_retry_base_sec <= self.oidc_retry_max_sec)
+        {
+            problems.push(
+                "`oidc_retry_base_sec` must be positive and at most `oidc_retry_max_sec`"
+                    .to_string(),
+            );
+        }
+
+        for (name, provider) in &self.providers {
+            if provider.hostnames.is_empty() && provider.hostname_regex.is_none() {
+                problems.push(format!(
+                    "provider '{name}' needs `hostnames` or `hostname_regex`"
+                ));
+            }
+        }
+
+        for (i, custom) in self.customizations.iter().enumerate() {
+            if let Some(status) = custom.config.deny_status {
+                if !(400..500).contains(&status) || status_code(status).is_none() {
+                    problems.push(format!(
+                        "customization {i}: `deny_status` {status} is not a 4xx status code"
+                    ));
+                }
+            }
+        }
+
+        let response_types = self.response_type.split_whitespace().collect::<Vec<_>>();
+        if response_types.contains(&"token") {
+            problems.push(format!(
+                "`response_type` '{}' requests an access token from the authorization endpoint (implicit flow), which is insecure",
+                self.response_type
+            ));
+        }
+        if !response_types.contains(&"code") {
+            problems.push(format!(
+                "`response_type` '{}' must include `code`",
+                self.response_type
+            ));
+        }
+        if self.customization_strategy != CustomizationStrategy::Union
+            && self.customizations.iter().any(|x| x.priority != 0)
+        {
+            warn!("customizations set `priority`, so `customization_strategy` is ignored wherever one of them matches");
+        }
+        if response_types.len() > 1 {
+            warn!(
+                "`response_type` '{}' is a hybrid flow, the IdP must still deliver `code` to /auth as a query parameter",
+                self.response_type
+            );
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
     pub fn uncustomized(&self) -> Customized<'_> {
         let required_roles: Vec<&str> = self.required_roles.iter().map(|x| &**x).collect();
 
         Customized {
             required_roles,
+            any_of_roles: any_of_group(&self.any_of_roles).into_iter().collect(),
+            required_scopes: self.required_scopes.iter().map(|x| &**x).collect(),
+            required_claims: claim_requirements(&self.required_claims).collect(),
             bypass: false,
+            accept_any_issuer: false,
+            client: None,
+            deny_status: None,
+            matched: vec![],
         }
     }
 
-    pub fn customized(&self, host: &str, path: &str, address: IpAddr) -> Customized<'_> {
+    /// `method` is `None` when the proxy didn't say. `methods`-restricted filters then match if they add requirements,
+    /// but not if they `bypass` or `accept_any_issuer`, so a missing method header never loosens access.
+    pub fn customized(
+        &self,
+        host: &str,
+        path: &str,
+        query: Option<&str>,
+        method: Option<&str>,
+        address: IpAddr,
+    ) -> Customized<'_> {
         let mut required_roles: Vec<&str> = self.required_roles.iter().map(|x| &**x).collect();
+        let mut any_of_roles: Vec<Vec<&str>> =
+            any_of_group(&self.any_of_roles).into_iter().collect();
+        let mut required_scopes: Vec<&str> = self.required_scopes.iter().map(|x| &**x).collect();
+        let mut required_claims: Vec<(&str, &[String])> =
+            claim_requirements(&self.required_claims).collect();
         let mut bypass = false;
+        let mut accept_any_issuer = false;
+        let mut client = None;
+        let mut deny_status = None;
 
-        for custom in &self.customizations {
-            if custom.filter.matches(host, path, address) {
-                required_roles.extend(custom.config.required_roles.iter().map(|x| &**x));
-                if custom.config.bypass {
-                    bypass = true;
-                }
+        let mut matched = self
+            .customizations
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| x.filter.matches(host, path, query, method, address))
+            .filter(|(_, x)| {
+                method.is_some()
+                    || x.filter.methods.is_empty()
+                    || !(x.config.bypass || x.config.accept_any_issuer)
+            })
+            .collect::<Vec<_>>();
+        let prioritized = matched.iter().any(|(_, x)| x.priority != 0);
+        match self.customization_strategy {
+            _ if prioritized => {
+                // max_by_key keeps the last maximum, so reverse to prefer the earliest rule on ties
+                matched = matched
+                    .into_iter()
+                    .rev()
+                    .max_by_key(|(_, x)| x.priority)
+                    .into_iter()
+                    .collect();
+            }
+            CustomizationStrategy::Union => (),
+            CustomizationStrategy::FirstMatch => matched.truncate(1),
+            CustomizationStrategy::MostSpecific => {
+                // max_by_key keeps the last maximum, so reverse to prefer the earliest rule on ties
+                matched = matched
+                    .into_iter()
+                    .rev()
+                    .max_by_key(|(_, x)| x.filter.specificity())
+                    .into_iter()
+                    .collect();
+            }
+        }
+
+        let indices = matched.iter().map(|(i, _)| *i).collect();
+        for (_, custom) in matched {
+            required_roles.extend(custom.config.required_roles.iter().map(|x| &**x));
+            any_of_roles.extend(any_of_group(&custom.config.any_of_roles));
+            required_scopes.extend(custom.config.required_scopes.iter().map(|x| &**x));
+            required_claims.extend(claim_requirements(&custom.config.required_claims));
+            if custom.config.bypass {
+                bypass = true;
+            }
+            if custom.config.accept_any_issuer {
+                accept_any_issuer = true;
+            }
+            if client.is_none() {
+                client = custom.config.client.as_ref();
+            }
+            if deny_status.is_none() {
+                deny_status = custom.config.deny_status;
             }
         }
         required_roles.sort();
         required_roles.dedup();
+        any_of_roles.sort();
+        any_of_roles.dedup();
+        required_scopes.sort();
+        required_scopes.dedup();
 
         Customized {
             required_roles,
+            any_of_roles,
+            required_scopes,
+            required_claims,
             bypass,
+            accept_any_issuer,
+            client,
+            deny_status,
+            matched: indices,
         }
     }
+
+    /// Every claim name referenced by a `required_claims` entry, globally or in a customization.
+    pub fn required_claim_names(&self) -> impl Iterator<Item = &str> {
+        self.required_claims
+            .keys()
+            .chain(
+                self.customizations
+                    .iter()
+                    .flat_map(|x| x.config.required_claims.keys()),
+            )
+            .map(|x| &**x)
+    }
+
+    pub fn customized_url(
+        &self,
+        url: &Url,
+        method: Option<&str>,
+        address: IpAddr,
+    ) -> Customized<'_> {
+        self.customized(
+            url.host_str().unwrap_or_default(),
+            url.path(),
+            url.query(),
+            method,
+            address,
+        )
+    }
+
+    /// Name of the entry of `providers` serving `host`, or `None` for the top-level provider.
+    pub fn provider_for_host(&self, host: &str) -> Option<&str> {
+        self.providers
+            .iter()
+            .find(|(_, x)| x.matches(host))
+            .map(|(name, _)| &**name)
+    }
+
+    pub fn provider_for_url(&self, url: &Url) -> Option<&str> {
+        self.provider_for_host(url.host_str().unwrap_or_default())
+    }
+
+    /// Finds the customization-level client registration with the given `client_id`, if any.
+    pub fn client_registration(&self, client_id: &str) -> Option<&ClientRegistration> {
+        self.customizations
+            .iter()
+            .filter_map(|x| x.config.client.as_ref())
+            .find(|x| x.client_id == client_id)
+    }
+}
+
+fn claim_requirements(
+    claims: &HashMap<String, Vec<String>>,
+) -> impl Iterator<Item = (&str, &[String])> {
+    claims.iter().map(|(name, allowed)| (&**name, &allowed[..]))
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Customization {
     pub filter: EndpointFilter,
     pub config: EndpointConfig,
+    /// If any matching customization sets a non-zero priority, only the highest-priority match applies, overriding `customization_strategy`.
+    /// Ties go to the earliest customization.
+    #[serde(default)]
+    pub priority: i32,
 }
 
 #[serde_as]
 #[derive(Serialize, Deserialize)]
 pub struct EndpointFilter {
     pub hostname: Option<String>,
+    /// Matches if the host equals any entry. `hostname` is shorthand for a one-element list; if both are set, either may match.
+    pub hostnames: Option<Vec<String>>,
     #[serde_as(as = "Option<DisplayFromStr>")]
     pub hostname_regex: Option<Regex>,
     pub path: Option<String>,
     pub path_prefix: Option<String>,
     #[serde_as(as = "Option<DisplayFromStr>")]
     pub path_regex: Option<Regex>,
+    /// Carve-outs: the filter never matches a path these match, even if the include conditions above do.
+    pub not_path_prefix: Option<String>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub not_path_regex: Option<Regex>,
+    /// Matched against the raw query string, empty if there is none.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub query_regex: Option<Regex>,
+    /// Every listed query parameter must be present with exactly this (decoded) value.
+    #[serde(default)]
+    pub query_params: HashMap<String, String>,
     #[serde(default)]
     pub cidr: Vec<IpCidr>,
+    /// If non-empty, the filter only applies to these HTTP methods, e.g. to require a role for writes but not reads.
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Compare `path`, `path_prefix` and `not_path_prefix` ignoring case. Regexes can use `(?i)` instead.
+    #[serde(default)]
+    pub case_insensitive: bool,
 }
 
 impl EndpointFilter {
-    pub fn matches(&self, host: &str, path: &str, address: IpAddr) -> bool {
-        if let Some(hostname) = &self.hostname {
-            if host != hostname {
+    /// Ranks how narrowly this filter selects paths, for `CustomizationStrategy::MostSpecific`.
+    pub fn specificity(&self) -> (u8, usize) {
+        if let Some(path) = &self.path {
+            (3, path.len())
+        } else if let Some(path_prefix) = &self.path_prefix {
+            (2, path_prefix.len())
+        } else if self.path_regex.is_some() {
+            (1, 0)
+        } else {
+            (0, 0)
+        }
+    }
+
+    pub fn matches(
+        &self,
+        host: &str,
+        path: &str,
+        query: Option<&str>,
+        method: Option<&str>,
+        address: IpAddr,
+    ) -> bool {
+        // hostnames are case-insensitive per DNS, regardless of `case_insensitive`
+        let host = host.to_ascii_lowercase();
+        if self.hostname.is_some() || self.hostnames.is_some() {
+            let mut hostnames = self.hostname.iter().chain(self.hostnames.iter().flatten());
+            if !hostnames.any(|x| x.eq_ignore_ascii_case(&host)) {
                 return false;
             }
         }
         if let Some(hostname_regex) = &self.hostname_regex {
-            if !hostname_regex.is_match(host) {
+            if !hostname_regex.is_match(&host) {
                 return false;
             }
         }
+        let fold = |x: &'_ str| -> Cow<'_, str> {
+            if self.case_insensitive {
+                Cow::Owned(x.to_lowercase())
+            } else {
+                Cow::Borrowed(x)
+            }
+        };
+        let folded_path = fold(path);
         if let Some(check_path) = &self.path {
-            if check_path != path {
+            if fold(check_path) != folded_path {
                 return false;
             }
         }
         if let Some(path_prefix) = &self.path_prefix {
-            if !path.starts_with(path_prefix) {
+            if !folded_path.starts_with(&*fold(path_prefix)) {
                 return false;
             }
         }
@@ -132,11 +1104,45 @@ impl EndpointFilter {
                 return false;
             }
         }
+        if let Some(not_path_prefix) = &self.not_path_prefix {
+            if folded_path.starts_with(&*fold(not_path_prefix)) {
+                return false;
+            }
+        }
+        if let Some(not_path_regex) = &self.not_path_regex {
+            if not_path_regex.is_match(path) {
+                return false;
+            }
+        }
+        if let Some(query_regex) = &self.query_regex {
+            if !query_regex.is_match(query.unwrap_or_default()) {
+                return false;
+            }
+        }
+        if !self.query_params.is_empty() {
+            let params: Vec<_> =
+                url::form_urlencoded::parse(query.unwrap_or_default().as_bytes()).collect();
+            let has = |(name, value): (&String, &String)| {
+                params
+                    .iter()
+                    .any(|(k, v)| &**k == name.as_str() && &**v == value.as_str())
+            };
+            if !self.query_params.iter().all(has) {
+                return false;
+            }
+        }
         if !self.cidr.is_empty() {
             if self.cidr.iter().all(|x| !x.contains(&address)) {
                 return false;
             }
         }
+        if let Some(method) = method {
+            if !self.methods.is_empty()
+                && !self.methods.iter().any(|x| x.eq_ignore_ascii_case(method))
+            {
+                return false;
+            }
+        }
         true
     }
 }
@@ -146,7 +1152,52 @@ pub struct EndpointConfig {
     #[serde(default)]
     pub required_roles: Vec<String>,
     #[serde(default)]
+    pub any_of_roles: Vec<String>,
+    #[serde(default)]
+    pub required_scopes: Vec<String>,
+    #[serde(default)]
+    pub required_claims: HashMap<String, Vec<String>>,
+    #[serde(default)]
     pub bypass: bool,
+    /// Accept sessions minted for a different `public` url (e.g. a sibling instance sharing `jwt_key`).
+    /// The signature is still verified, but any instance holding the key can then grant access here. Only use for narrowly-scoped, trusted internal paths.
+    #[serde(default)]
+    pub accept_any_issuer: bool,
+    /// Status returned instead of 403 when access is denied, e.g. 404 to hide that the resource exists. Must be a 4xx.
+    pub deny_status: Option<u16>,
+    /// Authenticate matching apps with their own client registration at the issuer. The first matching customization with a client wins.
+    pub client: Option<ClientRegistration>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize)]
+pub struct ProviderConfig {
+    pub issuer: Url,
+    pub client_id: String,
+    pub client_secret: String,
+    #[serde(default = "default_scopes")]
+    pub scopes: String,
+    #[serde(default)]
+    pub hostnames: Vec<String>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub hostname_regex: Option<Regex>,
+}
+
+impl ProviderConfig {
+    fn matches(&self, host: &str) -> bool {
+        self.hostnames.iter().any(|x| x.eq_ignore_ascii_case(host))
+            || self
+                .hostname_regex
+                .as_ref()
+                .map(|x| x.is_match(&host.to_ascii_lowercase()))
+                .unwrap_or(false)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ClientRegistration {
+    pub client_id: String,
+    pub client_secret: String,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -155,6 +1206,53 @@ pub struct OtelConfig {
     pub timeout_sec: f64,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct CorsConfig {
+    /// Origins such as `https://app.my.domain`, or `*` for any.
+    #[serde(default = "default_cors_any")]
+    pub allow_origins: Vec<String>,
+    /// Methods such as `GET`, or `*` for any.
+    #[serde(default = "default_cors_any")]
+    pub allow_methods: Vec<String>,
+    #[serde(default)]
+    pub allow_credentials: bool,
+    /// How long browsers may cache a preflight response.
+    pub max_age_sec: Option<u64>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allow_origins: default_cors_any(),
+            allow_methods: default_cors_any(),
+            allow_credentials: false,
+            max_age_sec: None,
+        }
+    }
+}
+
+impl CorsConfig {
+    pub fn any_origin(&self) -> bool {
+        self.allow_origins.iter().any(|x| x == "*")
+    }
+
+    pub fn any_method(&self) -> bool {
+        self.allow_methods.iter().any(|x| x == "*")
+    }
+}
+
+fn default_cors_any() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+fn default_shutdown_timeout_sec() -> u64 {
+    30
+}
+
+fn default_cookie_path() -> String {
+    "/".to_string()
+}
+
 fn default_true() -> bool {
     true
 }
@@ -167,10 +1265,153 @@ fn default_login_renew_seconds() -> i64 {
     1800
 }
 
+fn default_clock_skew_seconds() -> i64 {
+    30
+}
+
+fn default_cookie_max_size() -> usize {
+    3800
+}
+
+fn default_header_claims_separator() -> String {
+    ",".to_string()
+}
+
+fn default_access_token_header() -> String {
+    "Authorization".to_string()
+}
+
+fn default_roles_header_delimiter() -> String {
+    ",".to_string()
+}
+
+fn default_oidc_refresh_jitter_percent() -> f64 {
+    10.0
+}
+
+fn default_oidc_retry_base_sec() -> f64 {
+    1.0
+}
+
+fn default_oidc_retry_max_sec() -> f64 {
+    60.0
+}
+
+fn default_oidc_retry_warn_every() -> u32 {
+    10
+}
+
+fn default_discovery_cache_max_age_sec() -> i64 {
+    24 * 3600
+}
+
+fn default_www_authenticate_realm() -> String {
+    "oiplease".to_string()
+}
+
+fn default_validate_success_status() -> u16 {
+    200
+}
+
+fn default_max_header_bytes() -> usize {
+    64 * 1024
+}
+
+fn default_max_cookie_header_bytes() -> usize {
+    32 * 1024
+}
+
+fn default_min_refresh_interval_sec() -> i64 {
+    60
+}
+
 fn default_login_cache_minutes() -> i64 {
     240
 }
 
+fn default_max_total_cookie_bytes() -> usize {
+    16384
+}
+
+fn default_roles_claims() -> Vec<String> {
+    vec!["realm_access.roles".to_string()]
+}
+
+fn default_jwt_key_min_length() -> usize {
+    32
+}
+
+/// Keys nobody should be signing sessions with, compared case-insensitively.
+const WEAK_JWT_KEYS: &[&str] = &[
+    "secret",
+    "password",
+    "changeme",
+    "jwt_key",
+    "my_unique_jwt_key",
+    "oiplease",
+];
+
+fn jwt_key_weakness(key: &str, min_length: usize) -> Option<String> {
+    if key.len() < min_length {
+        return Some(format!(
+            "is {} bytes, shorter than `jwt_key_min_length` ({min_length})",
+            key.len()
+        ));
+    }
+    if WEAK_JWT_KEYS.iter().any(|x| x.eq_ignore_ascii_case(key)) {
+        return Some("is a well-known placeholder".to_string());
+    }
+    if key.chars().all(|x| Some(x) == key.chars().next()) {
+        return Some("repeats a single character".to_string());
+    }
+    None
+}
+
+/// Replaces `${VAR}` with the variable's value. A referenced variable that isn't set is an error rather than an empty string.
+fn interpolate_env(raw: &str, env: impl Fn(&str) -> Option<String>) -> anyhow::Result<String> {
+    let pattern = Regex::new(r"\$\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap();
+    let mut missing = vec![];
+    let out = pattern.replace_all(raw, |captures: &regex::Captures| {
+        env(&captures[1]).unwrap_or_else(|| {
+            missing.push(captures[1].to_string());
+            String::new()
+        })
+    });
+    if !missing.is_empty() {
+        bail!(
+            "config references unset environment variables: {}",
+            missing.join(", ")
+        );
+    }
+    Ok(out.into_owned())
+}
+
+/// Precedence: inline value, then `file`, then `env`.
+fn resolve_secret(
+    name: &str,
+    value: &mut String,
+    file: Option<&Path>,
+    env: Option<String>,
+) -> anyhow::Result<()> {
+    if !value.is_empty() {
+        return Ok(());
+    }
+    if let Some(file) = file {
+        let contents = std::fs::read_to_string(file)
+            .with_context(|| format!("failed to read `{name}_file` {}", file.display()))?;
+        *value = contents.trim_end_matches(['\r', '\n']).to_string();
+    } else if let Some(env) = env {
+        *value = env;
+    } else {
+        bail!("`{name}` is not set inline, via `{name}_file`, or via the environment");
+    }
+    Ok(())
+}
+
+fn default_response_type() -> String {
+    "code".to_string()
+}
+
 fn default_scopes() -> String {
     "openid email profile roles".to_string()
 }
@@ -184,24 +1425,777 @@ lazy_static::lazy_static! {
             base
         }
     };
-    pub static ref CONFIG: Config = {
-        serde_yaml::from_str(&std::fs::read_to_string(&*CONFIG_FILE).expect("failed to read config")).expect("failed to parse config")
-    };
-    pub static ref REDIRECT_URL: Url = {
-        let mut base = CONFIG.public.clone();
-        base.path_segments_mut().unwrap().pop_if_empty();
-        base.path_segments_mut().unwrap().push("auth");
-        base
+    /// The config as loaded at startup.
+    pub static ref CONFIG: Arc<Config> = Arc::new(read_config().unwrap_or_else(|e| panic!("{e:#}")));
+    /// The config as of the last SIGHUP, of which only the `RELOADABLE` fields are used. See `live`.
+    static ref LIVE: ArcSwap<Config> = ArcSwap::new(CONFIG.clone());
+    pub static ref REDIRECT_URL: Url = public_route_url(&CONFIG.public, "auth");
+    pub static ref LOGIN_URL: Url = public_route_url(&CONFIG.public, "login");
+    /// Path the routes are mounted at, with trailing slash. Follows `public` unless `route_base` is set.
+    pub static ref ROUTE_BASE: String = match &CONFIG.route_base {
+        Some(route_base) => path_base(route_base),
+        None => public_url_base(&CONFIG.public),
     };
-    pub static ref JWT_KEY: Hmac<Sha256> = {
-        Hmac::new_from_slice(CONFIG.jwt_key.as_bytes()).unwrap()
-    };
-    /// with trailing slash
-    pub static ref PUBLIC_URL_BASE: String = {
-        let mut out = CONFIG.public.path().to_string();
-        if !out.ends_with('/') {
-            out.push('/');
+}
+
+/// Fields a SIGHUP reload applies. Everything else keeps its startup value in `CONFIG` until a restart.
+const RELOADABLE: &[&str] = &[
+    "customizations",
+    "customization_strategy",
+    "required_roles",
+    "any_of_roles",
+    "required_scopes",
+    "required_claims",
+    "header_claims",
+    "header_claims_missing",
+];
+
+/// The latest config, for reading `RELOADABLE` fields.
+pub fn live() -> Arc<Config> {
+    LIVE.load_full()
+}
+
+fn read_config() -> anyhow::Result<Config> {
+    let raw = std::fs::read_to_string(&*CONFIG_FILE).context("failed to read config")?;
+    let config =
+        Config::load(&raw, |name| std::env::var(name).ok()).context("failed to load config")?;
+    if let Err(problems) = config.validate() {
+        bail!("invalid config:\n  - {}", problems.join("\n  - "));
+    }
+    Ok(config)
+}
+
+/// Re-reads the config file on every SIGHUP. An invalid file is logged and leaves the current config in place.
+pub async fn reload_on_sighup() {
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to install SIGHUP handler, config reloading is disabled: {e}");
+            return;
         }
-        out
     };
+    while hangup.recv().await.is_some() {
+        let config = match read_config() {
+            Ok(x) => x,
+            Err(e) => {
+                error!("not reloading config: {e:#}");
+                CONFIG_RELOADS.with_label_values(&["rejected"]).inc();
+                continue;
+            }
+        };
+        for field in config.restart_required(&CONFIG) {
+            warn!("`{field}` changed, which requires restart");
+        }
+        LIVE.store(Arc::new(config));
+        CONFIG_RELOADS.with_label_values(&["applied"]).inc();
+        CONFIG_GENERATION.inc();
+        info!("reloaded config from {}", &*CONFIG_FILE);
+    }
+}
+
+fn public_route_url(public: &Url, route: &str) -> Url {
+    let mut base = public.clone();
+    base.path_segments_mut().unwrap().pop_if_empty();
+    base.path_segments_mut().unwrap().push(route);
+    base
+}
+
+/// `roles` as an `any_of_roles` group, or `None` if it imposes nothing.
+fn any_of_group(roles: &[String]) -> Option<Vec<&str>> {
+    let mut group: Vec<&str> = roles.iter().map(|x| &**x).collect();
+    group.sort();
+    group.dedup();
+    (!group.is_empty()).then_some(group)
+}
+
+/// Whether `name` is an HTTP header field name, a non-empty RFC 9110 token.
+fn is_header_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|x| x.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&x))
+}
+
+/// Path of `public` with exactly one trailing slash, regardless of how it was configured.
+fn public_url_base(public: &Url) -> String {
+    path_base(public.path())
+}
+
+/// `path` with exactly one leading and one trailing slash.
+fn path_base(path: &str) -> String {
+    let path = path.trim_matches('/');
+    if path.is_empty() {
+        "/".to_string()
+    } else {
+        format!("/{path}/")
+    }
+}
+
+/// Full path a route is reachable at once nested under `base`.
+pub fn mounted_path(base: &str, route: &str) -> String {
+    format!(
+        "{}/{}",
+        base.trim_end_matches('/'),
+        route.trim_start_matches('/')
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::claims::roles_satisfy;
+
+    const BASE: &str = "
+bind: 0.0.0.0:80
+public: https://my.domain/oi/
+client_id: my_client_id
+client_secret: my_client_secret
+issuer: https://idp.my.domain/
+jwt_key: my_unique_jwt_key
+cookie_name: oiplease
+cookie_domain: my.domain
+success_header: X-Oi
+";
+
+    fn test_config(extra: &str) -> Config {
+        serde_yaml::from_str(&format!("{BASE}{extra}")).unwrap()
+    }
+
+    const OVERLAPPING: &str = "
+customizations:
+- filter:
+    path_prefix: /api
+  config:
+    required_roles: [api]
+- filter:
+    path_regex: ^/api/.*
+  config:
+    required_roles: [regex]
+- filter:
+    path_prefix: /api/admin
+  config:
+    required_roles: [admin]
+- filter:
+    path: /api/admin/health
+  config:
+    bypass: true
+";
+
+    fn roles_for(strategy: &str, path: &str) -> (Vec<String>, bool) {
+        let config = test_config(&format!(
+            "customization_strategy: {strategy}\n{OVERLAPPING}"
+        ));
+        let customized = config.customized(
+            "my.domain",
+            path,
+            None,
+            Some("GET"),
+            "127.0.0.1".parse().unwrap(),
+        );
+        (
+            customized
+                .required_roles
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            customized.bypass,
+        )
+    }
+
+    #[test]
+    fn test_jwt_key_weakness() {
+        assert!(jwt_key_weakness("short", 32).is_some());
+        assert!(jwt_key_weakness("SECRET", 1).is_some());
+        assert!(jwt_key_weakness(&"a".repeat(40), 32).is_some());
+        assert!(jwt_key_weakness("Jd8s1LkQ0vZp3Xr7Tn2Wm5Yc9Bf4Hg6A", 32).is_none());
+    }
+
+    #[test]
+    fn test_success_headers() {
+        assert_eq!(test_config("").success_headers, vec!["X-Oi"]);
+        let config: Config = serde_yaml::from_str(
+            &BASE.replace("success_header: X-Oi", "success_headers: [X-Oi, X-Auth]"),
+        )
+        .unwrap();
+        assert_eq!(config.success_headers, vec!["X-Oi", "X-Auth"]);
+    }
+
+    #[test]
+    fn test_customization_strategy() {
+        assert_eq!(
+            roles_for("union", "/api/admin/users"),
+            (vec!["admin".into(), "api".into(), "regex".into()], false)
+        );
+        assert_eq!(
+            roles_for("union", "/api/admin/health"),
+            (vec!["admin".into(), "api".into(), "regex".into()], true)
+        );
+
+        assert_eq!(
+            roles_for("first_match", "/api/admin/users"),
+            (vec!["api".into()], false)
+        );
+        assert_eq!(
+            roles_for("first_match", "/api/admin/health"),
+            (vec!["api".into()], false)
+        );
+
+        assert_eq!(
+            roles_for("most_specific", "/api/admin/users"),
+            (vec!["admin".into()], false)
+        );
+        assert_eq!(
+            roles_for("most_specific", "/api/admin/health"),
+            (vec![], true)
+        );
+        assert_eq!(
+            roles_for("most_specific", "/api/other"),
+            (vec!["api".into()], false)
+        );
+        assert_eq!(roles_for("most_specific", "/other"), (vec![], false));
+    }
+
+    #[test]
+    fn test_priority() {
+        let config = test_config(
+            "
+customizations:
+- filter:
+    path_prefix: /api
+  config:
+    required_roles: [api]
+- filter:
+    path_prefix: /api/admin
+  config:
+    required_roles: [admin]
+  priority: 10
+- filter:
+    path: /api/admin/health
+  config:
+    bypass: true
+  priority: 20
+",
+        );
+        let customized =
+            |path| config.customized("my.domain", path, None, None, "127.0.0.1".parse().unwrap());
+        assert_eq!(customized("/api/items").required_roles, vec!["api"]);
+        assert_eq!(customized("/api/admin/users").required_roles, vec!["admin"]);
+        assert_eq!(customized("/api/admin/users").matched, vec![1]);
+        let health = customized("/api/admin/health");
+        assert!(health.bypass);
+        assert!(health.required_roles.is_empty());
+
+        // priorities elsewhere leave the union of unprioritized matches alone
+        let config = test_config(
+            "
+customizations:
+- filter:
+    path_prefix: /api
+  config:
+    required_roles: [api]
+- filter:
+    path_prefix: /api/items
+  config:
+    required_roles: [items]
+- filter:
+    path_prefix: /admin
+  config:
+    required_roles: [admin]
+  priority: 10
+",
+        );
+        let customized = config.customized(
+            "my.domain",
+            "/api/items",
+            None,
+            None,
+            "127.0.0.1".parse().unwrap(),
+        );
+        assert_eq!(customized.required_roles, vec!["api", "items"]);
+    }
+
+    #[test]
+    fn test_any_of_groups() {
+        let config = test_config(
+            "
+any_of_roles: [staff, contractor]
+customizations:
+- filter:
+    path_prefix: /admin
+  config:
+    any_of_roles: [admin, owner]
+",
+        );
+        let admin = config.customized(
+            "my.domain",
+            "/admin",
+            None,
+            None,
+            "127.0.0.1".parse().unwrap(),
+        );
+        assert_eq!(
+            admin.any_of_roles,
+            vec![vec!["admin", "owner"], vec!["contractor", "staff"]]
+        );
+        let roles = |roles: &[&str]| roles.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        // the global group alone doesn't satisfy the endpoint's own
+        assert!(!roles_satisfy(&roles(&["staff"]), &[], &admin.any_of_roles));
+        assert!(!roles_satisfy(&roles(&["admin"]), &[], &admin.any_of_roles));
+        assert!(roles_satisfy(
+            &roles(&["staff", "owner"]),
+            &[],
+            &admin.any_of_roles
+        ));
+
+        let other = config.uncustomized();
+        assert!(roles_satisfy(&roles(&["staff"]), &[], &other.any_of_roles));
+    }
+
+    #[test]
+    fn test_public_url_base() {
+        for (public, base, redirect) in [
+            ("https://my.domain", "/", "https://my.domain/auth"),
+            ("https://my.domain/", "/", "https://my.domain/auth"),
+            ("https://my.domain/oi", "/oi/", "https://my.domain/oi/auth"),
+            ("https://my.domain/oi/", "/oi/", "https://my.domain/oi/auth"),
+            (
+                "https://my.domain/a/oi/",
+                "/a/oi/",
+                "https://my.domain/a/oi/auth",
+            ),
+        ] {
+            let public = Url::parse(public).unwrap();
+            assert_eq!(public_url_base(&public), base);
+            assert_eq!(public_route_url(&public, "auth").as_str(), redirect);
+            assert_eq!(mounted_path(base, "/validate"), format!("{base}validate"));
+        }
+    }
+
+    #[test]
+    fn test_route_base() {
+        for (route_base, base) in [("", "/"), ("/", "/"), ("oi", "/oi/"), ("/a/oi//", "/a/oi/")] {
+            assert_eq!(path_base(route_base), base);
+        }
+    }
+
+    #[test]
+    fn test_emitted_headers() {
+        let config = test_config(
+            "
+header_claims:
+  X-Oi-Email: email
+userinfo_header: X-Oi-Userinfo
+forward_access_token: true
+",
+        );
+        let mut headers = config.emitted_headers().collect::<Vec<_>>();
+        headers.sort();
+        assert_eq!(headers, vec!["X-Oi", "X-Oi-Email", "X-Oi-Userinfo"]);
+    }
+
+    #[test]
+    fn test_methods() {
+        let config = test_config(
+            "
+customizations:
+- filter:
+    path_prefix: /api
+    methods: [POST, PUT, DELETE]
+  config:
+    required_roles: [writer]
+",
+        );
+        let roles = |method| {
+            config
+                .customized(
+                    "my.domain",
+                    "/api/items",
+                    None,
+                    method,
+                    "127.0.0.1".parse().unwrap(),
+                )
+                .required_roles
+        };
+        assert!(roles(Some("GET")).is_empty());
+        assert_eq!(roles(Some("POST")), vec!["writer"]);
+        assert_eq!(roles(Some("delete")), vec!["writer"]);
+        // unknown method: the restriction applies rather than silently not
+        assert_eq!(roles(None), vec!["writer"]);
+
+        let config = test_config(
+            "
+customizations:
+- filter:
+    path_prefix: /api
+    methods: [OPTIONS]
+  config:
+    bypass: true
+",
+        );
+        let bypass = |method| {
+            config
+                .customized(
+                    "my.domain",
+                    "/api/items",
+                    None,
+                    method,
+                    "127.0.0.1".parse().unwrap(),
+                )
+                .bypass
+        };
+        assert!(bypass(Some("OPTIONS")));
+        assert!(!bypass(Some("GET")));
+        assert!(!bypass(None));
+    }
+
+    #[test]
+    fn test_query_filters() {
+        let config = test_config(
+            "
+customizations:
+- filter:
+    query_params:
+      admin: 'true'
+  config:
+    required_roles: [admin]
+- filter:
+    query_regex: (^|&)debug=
+  config:
+    required_roles: [debug]
+- filter:
+    path_prefix: /api
+  config:
+    required_roles: [api]
+",
+        );
+        let roles = |url: &str| {
+            let url = Url::parse(url).unwrap();
+            config
+                .customized_url(&url, Some("GET"), "127.0.0.1".parse().unwrap())
+                .required_roles
+        };
+        assert_eq!(roles("https://my.domain/api"), vec!["api"]);
+        assert_eq!(
+            roles("https://my.domain/api?admin=true"),
+            vec!["admin", "api"]
+        );
+        assert!(roles("https://my.domain/?admin=false").is_empty());
+        assert_eq!(roles("https://my.domain/?x=1&debug=1"), vec!["debug"]);
+        assert!(roles("https://my.domain/?nodebug=1").is_empty());
+    }
+
+    #[test]
+    fn test_hostnames() {
+        let config = test_config(
+            "
+customizations:
+- filter:
+    hostnames: [a.my.domain, b.my.domain]
+  config:
+    required_roles: [shared]
+- filter:
+    hostname: c.my.domain
+  config:
+    required_roles: [single]
+",
+        );
+        let roles = |host| {
+            config
+                .customized(host, "/", None, None, "127.0.0.1".parse().unwrap())
+                .required_roles
+        };
+        assert_eq!(roles("a.my.domain"), vec!["shared"]);
+        assert_eq!(roles("b.my.domain"), vec!["shared"]);
+        assert_eq!(roles("c.my.domain"), vec!["single"]);
+        assert!(roles("d.my.domain").is_empty());
+    }
+
+    #[test]
+    fn test_path_exclusions() {
+        let config = test_config(
+            "
+customizations:
+- filter:
+    path_prefix: /api
+    not_path_prefix: /api/public
+    not_path_regex: ^/api/[^/]+/health$
+  config:
+    required_roles: [api]
+",
+        );
+        let roles = |path| {
+            config
+                .customized("my.domain", path, None, None, "127.0.0.1".parse().unwrap())
+                .required_roles
+        };
+        assert_eq!(roles("/api/items"), vec!["api"]);
+        assert!(roles("/api/public/logo.png").is_empty());
+        assert!(roles("/api/items/health").is_empty());
+        assert_eq!(roles("/api/items/health/deep"), vec!["api"]);
+    }
+
+    #[test]
+    fn test_case_insensitive() {
+        let config = test_config(
+            "
+customizations:
+- filter:
+    hostname: Admin.My.Domain
+    path_prefix: /Admin
+    case_insensitive: true
+  config:
+    required_roles: [admin]
+- filter:
+    hostname: my.domain
+    path: /API
+  config:
+    required_roles: [api]
+",
+        );
+        let roles = |host, path| {
+            config
+                .customized(host, path, None, None, "127.0.0.1".parse().unwrap())
+                .required_roles
+        };
+        assert_eq!(roles("admin.my.domain", "/admin/users"), vec!["admin"]);
+        assert_eq!(roles("ADMIN.my.domain", "/ADMIN"), vec!["admin"]);
+        assert!(roles("admin.my.domain", "/other").is_empty());
+        // case-sensitive by default, except for the hostname
+        assert_eq!(roles("My.Domain", "/API"), vec!["api"]);
+        assert!(roles("my.domain", "/api").is_empty());
+    }
+
+    #[test]
+    fn test_providers() {
+        let config = test_config(
+            "
+providers:
+  partners:
+    issuer: https://partners-idp.my.domain/
+    client_id: partners
+    client_secret: partners_secret
+    hostnames: [partners.my.domain]
+  staff:
+    issuer: https://staff-idp.my.domain/
+    client_id: staff
+    client_secret: staff_secret
+    hostname_regex: ^.*\\.internal\\.my\\.domain$
+",
+        );
+        assert_eq!(
+            config.provider_for_host("Partners.my.domain"),
+            Some("partners")
+        );
+        assert_eq!(
+            config.provider_for_host("wiki.internal.my.domain"),
+            Some("staff")
+        );
+        assert_eq!(config.provider_for_host("my.domain"), None);
+        assert_eq!(config.providers["staff"].scopes, default_scopes());
+    }
+
+    #[test]
+    fn test_public_paths() {
+        let config = test_config(
+            "
+public_paths: [/favicon.ico, /static/*]
+",
+        );
+        assert!(config.is_public_path("/favicon.ico"));
+        assert!(config.is_public_path("/static/app.js"));
+        assert!(!config.is_public_path("/favicon.ico.bak"));
+        assert!(!config.is_public_path("/staticfile"));
+        assert!(!config.is_public_path("/"));
+    }
+
+    #[test]
+    fn test_redirect_allowlist() {
+        let allowed =
+            |config: &Config, url: &str| config.is_redirect_allowed(&Url::parse(url).unwrap());
+
+        let config = test_config("");
+        assert!(allowed(&config, "https://anything.example/"));
+        assert!(!allowed(&config, "javascript://my.domain/%0aalert(1)"));
+
+        let config = test_config("redirect_allowlist_default: same_origin\n");
+        assert!(allowed(&config, "https://my.domain/app"));
+        assert!(!allowed(&config, "https://app.my.domain/"));
+        assert!(!allowed(&config, "http://my.domain/"));
+
+        let config = test_config(
+            "
+redirect_allowlist: [https://app.my.domain, '*.internal.my.domain']
+",
+        );
+        assert!(allowed(&config, "https://app.my.domain/x"));
+        assert!(!allowed(&config, "http://app.my.domain/x"));
+        assert!(allowed(&config, "http://a.internal.my.domain/"));
+        assert!(!allowed(&config, "https://internal.my.domain/"));
+        assert!(!allowed(&config, "https://evilinternal.my.domain/"));
+        assert!(!allowed(&config, "https://my.domain/"));
+    }
+
+    #[test]
+    fn test_roles_claim_path() {
+        assert_eq!(test_config("").roles_claims, vec!["realm_access.roles"]);
+        assert_eq!(
+            test_config("roles_claim_path: groups\n").roles_claims,
+            vec!["groups"]
+        );
+        assert_eq!(
+            test_config("roles_claims: [groups, roles]\n").roles_claims,
+            vec!["groups", "roles"]
+        );
+    }
+
+    #[test]
+    fn test_load_secrets() {
+        let env = |name: &str| match name {
+            "IDP_HOST" => Some("idp.my.domain".to_string()),
+            "OIPLEASE_JWT_KEY" => Some("from the environment".to_string()),
+            _ => None,
+        };
+        let raw = BASE
+            .replace("client_secret: my_client_secret\n", "")
+            .replace("jwt_key: my_unique_jwt_key\n", "")
+            .replace("https://idp.my.domain/", "https://${IDP_HOST}/");
+
+        let dir = std::env::temp_dir().join(format!("oiplease-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let secret_file = dir.join("client_secret");
+        std::fs::write(&secret_file, "from a file\n").unwrap();
+        let with_file = format!("{raw}client_secret_file: {}\n", secret_file.display());
+
+        let config = Config::load(&with_file, env).unwrap();
+        assert_eq!(config.issuer.as_str(), "https://idp.my.domain/");
+        assert_eq!(config.client_secret, "from a file");
+        assert_eq!(config.jwt_key, "from the environment");
+
+        let inline = format!("{with_file}client_secret: inline\n");
+        assert_eq!(Config::load(&inline, env).unwrap().client_secret, "inline");
+
+        let e = Config::load(&raw, env).unwrap_err();
+        assert!(format!("{e:#}").contains("`client_secret`"));
+        let e = Config::load("issuer: ${NOPE}", env).unwrap_err();
+        assert!(format!("{e:#}").contains("NOPE"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_restart_required() {
+        let running = test_config("");
+        let reloaded = test_config(
+            "
+required_roles: [user]
+customizations:
+- filter:
+    path_prefix: /admin
+  config:
+    required_roles: [admin]
+",
+        );
+        assert!(reloaded.restart_required(&running).is_empty());
+
+        let mut moved = test_config("");
+        moved.bind = "0.0.0.0:9000".parse().unwrap();
+        moved.jwt_key = "another key entirely".to_string();
+        let mut changed = moved.restart_required(&running);
+        changed.sort();
+        assert_eq!(changed, vec!["bind", "jwt_key"]);
+    }
+
+    #[test]
+    fn test_cors() {
+        let config = test_config("allow_weak_jwt_key: true\n");
+        assert!(config.cors.any_origin() && config.cors.any_method());
+        config.validate().unwrap();
+
+        let mut config = test_config(
+            "
+allow_weak_jwt_key: true
+cors:
+  allow_origins: [https://app.my.domain]
+  allow_methods: [GET]
+  allow_credentials: true
+",
+        );
+        config.validate().unwrap();
+        config.cors.allow_origins.push("*".to_string());
+        assert!(config.validate().is_err());
+        config.cors.allow_origins = vec!["https://app.my.domain/login".to_string()];
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_reports_every_problem() {
+        let mut config = test_config("allow_weak_jwt_key: true\n");
+        config.validate().unwrap();
+        config.public = Url::parse("https://my.domain/oi/?next=x").unwrap();
+        config.clock_skew_seconds = -1;
+        config
+            .header_claims
+            .insert("X-User Name".to_string(), "name".to_string());
+        let problems = config.validate().unwrap_err();
+        assert_eq!(problems.len(), 3, "{problems:?}");
+        assert!(problems[0].starts_with("`public`"));
+        assert!(problems[1].contains("'X-User Name'"));
+        assert!(problems[2].starts_with("`clock_skew_seconds`"));
+
+        assert!(is_header_name("X-Auth-Request-Email"));
+        assert!(!is_header_name(""));
+        assert!(!is_header_name("X-Auth:Email"));
+    }
+
+    #[test]
+    fn test_jwt_keys_validation() {
+        let mut config = test_config("allow_weak_jwt_key: true\njwt_key_retired: true\n");
+        assert!(config.validate().is_err());
+        config.jwt_keys.push(JwtKeyConfig {
+            kid: "new".to_string(),
+            key: "a key that is long enough".to_string(),
+            primary: true,
+        });
+        config.validate().unwrap();
+
+        config.jwt_algorithm = JwtAlgorithm::RS256;
+        let problems = config.validate().unwrap_err();
+        assert!(problems[0].starts_with("`jwt_keys` only applies to HS256"));
+    }
+
+    #[test]
+    fn test_cookie_secure() {
+        assert_eq!(test_config("").cookie_secure, CookieSecure::Fixed(true));
+        let fixed = test_config("cookie_secure: false\n").cookie_secure;
+        assert_eq!(fixed, CookieSecure::Fixed(false));
+        assert!(!fixed.resolve(Some("https")));
+
+        let auto = test_config("cookie_secure: auto\n").cookie_secure;
+        assert!(auto.resolve(Some("https")));
+        assert!(auto.resolve(Some("HTTPS, http")));
+        assert!(!auto.resolve(Some("http")));
+        assert!(!auto.resolve(None));
+
+        let mut config = test_config("allow_weak_jwt_key: true\ncookie_secure: auto\n");
+        config.cookie_name = "__Host-oiplease".to_string();
+        config.cookie_domain.clear();
+        config.validate().unwrap();
+        // plain http requests would get SameSite=None without Secure, which browsers drop
+        config.cookie_same_site = CookieSameSite::None;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_host_prefix() {
+        let mut config = test_config("allow_weak_jwt_key: true\n");
+        config.cookie_name = "__Host-oiplease".to_string();
+        assert!(config.validate().is_err());
+        config.cookie_domain.clear();
+        config.validate().unwrap();
+        config.cookie_secure = CookieSecure::Fixed(false);
+        assert!(config.validate().is_err());
+        config.cookie_secure = CookieSecure::Fixed(true);
+        config.cookie_path = "/oi".to_string();
+        assert!(config.validate().is_err());
+    }
 }
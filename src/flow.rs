@@ -0,0 +1,192 @@
+use anyhow::Result;
+use axol_http::typed_headers::Cookie as CookieHeader;
+use base64::{engine::general_purpose, Engine as _};
+use chrono::Utc;
+use cookie::{Cookie, CookieBuilder, SameSite};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use jwt::{SignWithKey, VerifyWithKey};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::config::{CookieSameSite, CONFIG, REDIRECT_URL};
+
+lazy_static::lazy_static! {
+    /// Derived from `jwt_key` under its own label, so a flow cookie can never pass for a session or vice versa.
+    static ref FLOW_KEY: Hmac<Sha256> = flow_key(CONFIG.jwt_key.as_bytes());
+}
+
+/// How long a login may take between `/login` and the `/auth` callback, when `max_auth_flow_age_sec` isn't set.
+const DEFAULT_FLOW_TTL_SEC: i64 = 600;
+/// The flow cookie outlives `exp` by this much, so a callback that took too long can be told from one without a flow.
+const FLOW_COOKIE_GRACE_SEC: i64 = 3600;
+
+/// Why a callback has no usable login flow.
+pub enum FlowError {
+    /// No flow cookie, or one that isn't correctly signed.
+    Missing,
+    /// The flow is valid but older than `max_auth_flow_age_sec`.
+    Expired,
+}
+
+/// Transient login state carried from `/login` to `/auth`, since oiplease keeps no server side state.
+#[derive(Serialize, Deserialize)]
+pub struct FlowState {
+    /// PKCE code verifier, sent to the token endpoint during the code exchange.
+    pub verifier: String,
+    /// HMAC of the `state` sent to the IdP, which must come back unchanged on the callback.
+    pub state_mac: String,
+    /// Target the flow was started for, so a callback can't be pointed elsewhere.
+    pub url: Url,
+    /// OIDC nonce sent to the IdP, which the id_token must carry back so it can't be replayed into another flow.
+    pub nonce: String,
+    /// Set when this flow restarts one whose code the IdP rejected, so a second rejection ends the login instead of looping.
+    #[serde(default)]
+    pub retried: bool,
+    pub exp: i64,
+}
+
+impl FlowState {
+    /// Starts a flow towards `url`, returning it with the `state` to send to the IdP.
+    pub fn new(url: Url, retried: bool) -> (Self, String) {
+        let state = random_token();
+        let flow = FlowState {
+            verifier: random_token(),
+            state_mac: general_purpose::URL_SAFE_NO_PAD.encode(state_mac(&state)),
+            url,
+            nonce: random_token(),
+            retried,
+            exp: Utc::now().timestamp() + flow_ttl(),
+        };
+        (flow, state)
+    }
+
+    /// Whether the callback's `state` and `url` are the ones this flow was started with.
+    pub fn matches(&self, state: &str, url: &Url) -> bool {
+        let Ok(mac) = general_purpose::URL_SAFE_NO_PAD.decode(&self.state_mac) else {
+            return false;
+        };
+        let state_ok = FLOW_KEY
+            .clone()
+            .chain_update(state)
+            .verify_slice(&mac)
+            .is_ok();
+        state_ok && &self.url == url
+    }
+
+    /// PKCE `S256` code challenge for the verifier.
+    pub fn challenge(&self) -> String {
+        code_challenge(&self.verifier)
+    }
+
+    /// Reads the flow cookie, if present, correctly signed, and not expired.
+    pub fn from_cookies(cookies: Option<&CookieHeader>) -> Result<Self, FlowError> {
+        let flow: FlowState = cookies
+            .and_then(|x| x.get(&flow_cookie_name()))
+            .and_then(|x| x.verify_with_key(&*FLOW_KEY).ok())
+            .ok_or(FlowError::Missing)?;
+        if flow.exp < Utc::now().timestamp() {
+            return Err(FlowError::Expired);
+        }
+        Ok(flow)
+    }
+
+    pub fn cookie(&self, secure: bool) -> Result<Cookie<'static>> {
+        let value = self.sign_with_key(&*FLOW_KEY)?;
+        Ok(flow_cookie(
+            value,
+            flow_ttl() + FLOW_COOKIE_GRACE_SEC,
+            secure,
+        ))
+    }
+
+    pub fn clear_cookie(secure: bool) -> Cookie<'static> {
+        flow_cookie(String::new(), 0, secure)
+    }
+}
+
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn state_mac(state: &str) -> Vec<u8> {
+    FLOW_KEY
+        .clone()
+        .chain_update(state)
+        .finalize()
+        .into_bytes()
+        .to_vec()
+}
+
+fn flow_key(secret: &[u8]) -> Hmac<Sha256> {
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, secret)
+        .expand(b"oiplease login flow", &mut key)
+        .expect("32 bytes is a valid hkdf output length");
+    Hmac::new_from_slice(&key).expect("hmac accepts any key length")
+}
+
+fn flow_ttl() -> i64 {
+    CONFIG.max_auth_flow_age_sec.unwrap_or(DEFAULT_FLOW_TTL_SEC)
+}
+
+/// The IdP redirects back cross-site, so the flow cookie must be at least Lax even when the session cookie is Strict.
+fn flow_same_site() -> SameSite {
+    match CONFIG.cookie_same_site {
+        CookieSameSite::None => SameSite::None,
+        CookieSameSite::Strict | CookieSameSite::Lax => SameSite::Lax,
+    }
+}
+
+fn flow_cookie_name() -> String {
+    format!("{}_flow", CONFIG.cookie_name)
+}
+
+/// Scoped to the callback so it isn't sent with every proxied request, except for `__Host-` names which must use Path=/.
+fn flow_cookie(value: String, max_age: i64, secure: bool) -> Cookie<'static> {
+    let path = if CONFIG.cookie_host_prefixed() {
+        "/".to_string()
+    } else {
+        REDIRECT_URL.path().to_string()
+    };
+    CookieBuilder::new(flow_cookie_name(), value)
+        .http_only(true)
+        .secure(secure)
+        .same_site(flow_same_site())
+        .max_age(cookie::time::Duration::seconds(max_age))
+        .path(path)
+        .finish()
+}
+
+fn code_challenge(verifier: &str) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(verifier))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_code_challenge() {
+        // RFC 7636 appendix B
+        assert_eq!(
+            code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGEjjQzY9I"
+        );
+    }
+
+    #[test]
+    fn test_flow_key() {
+        let mac = |key: Hmac<Sha256>| key.chain_update("state").finalize().into_bytes();
+        let session_key = Hmac::<Sha256>::new_from_slice(b"my_unique_jwt_key").unwrap();
+        assert_ne!(mac(flow_key(b"my_unique_jwt_key")), mac(session_key));
+        assert_eq!(
+            mac(flow_key(b"my_unique_jwt_key")),
+            mac(flow_key(b"my_unique_jwt_key"))
+        );
+    }
+}
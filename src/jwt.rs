@@ -1,12 +1,23 @@
 use std::collections::HashMap;
 
-use anyhow::Result;
-use jwt::{SignWithKey, VerifyWithKey};
+use anyhow::{bail, Context, Result};
+use hmac::{Hmac, Mac};
+use jwt::{Header, PKeyWithDigest, SignWithKey, Token, VerifyWithKey};
 use openid::Bearer;
+use openssl::{
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{Id, PKey, Private, Public},
+};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::Sha256;
 use url::Url;
 
-use crate::config::JWT_KEY;
+use crate::{
+    claims::{any_allowed, roles_satisfy},
+    config::{Config, JwtAlgorithm, CONFIG},
+};
 
 #[derive(Serialize, Deserialize)]
 pub struct JwtClaims {
@@ -15,20 +26,351 @@ pub struct JwtClaims {
     pub iss: i64,
     pub exp: i64,
     pub roles: Vec<String>,
+    /// When the tokens were last refreshed, to rate-limit refreshes.
+    #[serde(default)]
+    pub refreshed_at: i64,
+    /// When `roles` were last derived from the IdP.
+    #[serde(default)]
+    pub roles_at: i64,
+    /// OAuth scopes granted at login or the last refresh, checked against `required_scopes`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scopes: Vec<String>,
+    /// Values of the claims referenced by `required_claims`, captured at login.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub claim_values: HashMap<String, Vec<String>>,
+    /// Claims forwarded via `userinfo_header`.
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub userinfo: Map<String, Value>,
+    /// Subject and IdP session id, enough to build a logout request without keeping the id_token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
+    /// Random id of this session, kept across renewals, for revoking it individually.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    /// When the user logged in. Unlike `iss`, not updated on renewal.
+    #[serde(default)]
+    pub login_at: i64,
+    /// Entry of `providers` that minted this session, when not the top-level provider.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    /// Client registration that minted this session, when not the global one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
     #[serde(flatten)]
     pub bearer: Bearer,
 }
 
+/// A session signed with a `kid` that isn't configured, distinct from a bad signature so rotation mistakes are recognizable.
+#[derive(Debug)]
+pub struct UnknownKid(pub String);
+
+impl std::fmt::Display for UnknownKid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown kid '{}'", self.0)
+    }
+}
+
+impl std::error::Error for UnknownKid {}
+
+pub struct HmacKeys {
+    /// `kid` of the signing key. `None` is the unnamed `jwt_key`, whose sessions carry no `kid`.
+    signing: Option<String>,
+    keys: Vec<(Option<String>, Hmac<Sha256>)>,
+}
+
+impl HmacKeys {
+    fn key(&self, kid: Option<&str>) -> Option<&Hmac<Sha256>> {
+        self.keys
+            .iter()
+            .find(|(x, _)| x.as_deref() == kid)
+            .map(|(_, key)| key)
+    }
+}
+
+/// Keys signing and verifying the session JWT, per `jwt_algorithm`.
+pub enum SessionKeys {
+    Hmac(HmacKeys),
+    /// Without a private key this instance can verify sessions but not mint them.
+    Asymmetric {
+        signing: Option<PKeyWithDigest<Private>>,
+        verifying: PKeyWithDigest<Public>,
+    },
+}
+
+impl SessionKeys {
+    pub fn load(config: &Config) -> Result<Self> {
+        let (id, curve) = match config.jwt_algorithm {
+            JwtAlgorithm::HS256 => {
+                let mut keys = vec![];
+                if !config.jwt_key_retired {
+                    keys.push((None, Hmac::new_from_slice(config.jwt_key.as_bytes())?));
+                }
+                let mut signing = None;
+                for entry in &config.jwt_keys {
+                    keys.push((
+                        Some(entry.kid.clone()),
+                        Hmac::new_from_slice(entry.key.as_bytes())?,
+                    ));
+                    if entry.primary {
+                        signing = Some(entry.kid.clone());
+                    }
+                }
+                return Ok(SessionKeys::Hmac(HmacKeys { signing, keys }));
+            }
+            JwtAlgorithm::RS256 => (Id::RSA, None),
+            JwtAlgorithm::ES256 => (Id::EC, Some(Nid::X9_62_PRIME256V1)),
+        };
+        let read = |path: &Option<std::path::PathBuf>, name: &str| -> Result<Option<Vec<u8>>> {
+            path.as_ref()
+                .map(|x| std::fs::read(x).with_context(|| format!("failed to read `{name}`")))
+                .transpose()
+        };
+        let signing = read(&config.jwt_private_key_file, "jwt_private_key_file")?
+            .map(|pem| PKey::private_key_from_pem(&pem))
+            .transpose()
+            .context("`jwt_private_key_file` is not a PEM private key")?;
+        let verifying = match read(&config.jwt_public_key_file, "jwt_public_key_file")? {
+            Some(pem) => PKey::public_key_from_pem(&pem)
+                .context("`jwt_public_key_file` is not a PEM public key")?,
+            None => match &signing {
+                Some(private) => PKey::public_key_from_pem(&private.public_key_to_pem()?)?,
+                None => bail!(
+                    "`jwt_algorithm` {:?} needs `jwt_public_key_file` or `jwt_private_key_file`",
+                    config.jwt_algorithm
+                ),
+            },
+        };
+        check_key_type(&verifying, id, curve, config.jwt_algorithm)?;
+        if let Some(signing) = &signing {
+            check_key_type(signing, id, curve, config.jwt_algorithm)?;
+        }
+        Ok(SessionKeys::Asymmetric {
+            signing: signing.map(|key| PKeyWithDigest {
+                digest: MessageDigest::sha256(),
+                key,
+            }),
+            verifying: PKeyWithDigest {
+                digest: MessageDigest::sha256(),
+                key: verifying,
+            },
+        })
+    }
+}
+
+fn check_key_type<T: openssl::pkey::HasPublic>(
+    key: &PKey<T>,
+    id: Id,
+    curve: Option<Nid>,
+    algorithm: JwtAlgorithm,
+) -> Result<()> {
+    let curve_ok = match curve {
+        None => true,
+        Some(curve) => key.ec_key().ok().and_then(|x| x.group().curve_name()) == Some(curve),
+    };
+    if key.id() != id || !curve_ok {
+        bail!("session key type does not match `jwt_algorithm` {algorithm:?}");
+    }
+    Ok(())
+}
+
+lazy_static::lazy_static! {
+    pub static ref SESSION_KEYS: SessionKeys = SessionKeys::load(&CONFIG).expect("failed to load session keys");
+}
+
 impl JwtClaims {
     pub fn sign(&self) -> Result<String> {
-        Ok(self.sign_with_key(&*JWT_KEY)?)
+        self.sign_with(&SESSION_KEYS)
     }
 
     pub fn validate(value: &str) -> Result<Self> {
-        Ok(value.verify_with_key(&*JWT_KEY)?)
+        Self::validate_with(value, &SESSION_KEYS)
+    }
+
+    fn sign_with(&self, keys: &SessionKeys) -> Result<String> {
+        Ok(match keys {
+            SessionKeys::Hmac(hmac) => {
+                let key = hmac
+                    .key(hmac.signing.as_deref())
+                    .context("missing signing key")?;
+                let header = Header {
+                    key_id: hmac.signing.clone(),
+                    ..Default::default()
+                };
+                Token::new(header, self)
+                    .sign_with_key(key)?
+                    .as_str()
+                    .to_string()
+            }
+            SessionKeys::Asymmetric {
+                signing: Some(key), ..
+            } => self.sign_with_key(key)?,
+            SessionKeys::Asymmetric { signing: None, .. } => {
+                bail!("no `jwt_private_key_file`, this instance can only verify sessions")
+            }
+        })
+    }
+
+    fn validate_with(value: &str, keys: &SessionKeys) -> Result<Self> {
+        Ok(match keys {
+            SessionKeys::Hmac(hmac) => {
+                let token: Token<Header, JwtClaims, _> = Token::parse_unverified(value)?;
+                let kid = token.header().key_id.clone();
+                let Some(key) = hmac.key(kid.as_deref()) else {
+                    return Err(UnknownKid(kid.unwrap_or_default()).into());
+                };
+                let (_, claims) = token.verify_with_key(key)?.into();
+                claims
+            }
+            SessionKeys::Asymmetric { verifying, .. } => value.verify_with_key(verifying)?,
+        })
     }
 
-    pub fn has_required_roles(&self, roles: &[&str]) -> bool {
-        roles.iter().all(|x| self.roles.iter().any(|y| y == x))
+    pub fn has_required_roles(&self, all_of: &[&str], any_of: &[Vec<&str>]) -> bool {
+        roles_satisfy(&self.roles, all_of, any_of)
+    }
+
+    pub fn has_required_scopes(&self, scopes: &[&str]) -> bool {
+        scopes
+            .iter()
+            .all(|x| self.scopes.iter().any(|scope| scope == x))
+    }
+
+    pub fn has_required_claims(&self, claims: &[(&str, &[String])]) -> bool {
+        claims.iter().all(|(name, allowed)| {
+            self.claim_values
+                .get(*name)
+                .map(|values| any_allowed(values, allowed))
+                .unwrap_or(false)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oidc::renewed_bearer;
+    use openssl::{ec::EcGroup, ec::EcKey};
+
+    fn claims() -> JwtClaims {
+        serde_json::from_value(serde_json::json!({
+            "issuer": "https://my.domain/oi/",
+            "claims": {},
+            "iss": 0,
+            "exp": 0,
+            "roles": ["admin"],
+            "access_token": "",
+            "token_type": "Bearer",
+        }))
+        .unwrap()
+    }
+
+    fn hmac_keys(signing: Option<&str>, kids: &[Option<&str>]) -> SessionKeys {
+        SessionKeys::Hmac(HmacKeys {
+            signing: signing.map(String::from),
+            keys: kids
+                .iter()
+                .map(|kid| {
+                    let key = format!("key for {kid:?}");
+                    (
+                        kid.map(String::from),
+                        Hmac::new_from_slice(key.as_bytes()).unwrap(),
+                    )
+                })
+                .collect(),
+        })
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let legacy = claims().sign_with(&hmac_keys(None, &[None])).unwrap();
+        let old = claims()
+            .sign_with(&hmac_keys(Some("old"), &[None, Some("old")]))
+            .unwrap();
+
+        // new key added and made primary, old still accepted
+        let rotating = hmac_keys(Some("new"), &[None, Some("old"), Some("new")]);
+        let new = claims().sign_with(&rotating).unwrap();
+        for token in [&legacy, &old, &new] {
+            assert!(JwtClaims::validate_with(token, &rotating).is_ok());
+        }
+
+        // old key removed
+        let rotated = hmac_keys(Some("new"), &[None, Some("new")]);
+        assert!(JwtClaims::validate_with(&new, &rotated).is_ok());
+        let e = JwtClaims::validate_with(&old, &rotated).unwrap_err();
+        assert!(e.is::<UnknownKid>());
+
+        // `jwt_key_retired`: kid-less sessions signed with `jwt_key` stop verifying
+        let retired = hmac_keys(Some("new"), &[Some("new")]);
+        assert!(JwtClaims::validate_with(&new, &retired).is_ok());
+        let e = JwtClaims::validate_with(&legacy, &retired).unwrap_err();
+        assert!(e.is::<UnknownKid>());
+
+        // known kid, bad signature
+        let body = &new[..new.rfind('.').unwrap()];
+        let signature = &legacy[legacy.rfind('.').unwrap()..];
+        let e = JwtClaims::validate_with(&format!("{body}{signature}"), &rotated).unwrap_err();
+        assert!(!e.is::<UnknownKid>());
+    }
+
+    #[test]
+    fn test_rotated_refresh_token() {
+        let keys = hmac_keys(None, &[None]);
+        let bearer = |refresh_token: Option<&str>| -> Bearer {
+            serde_json::from_value(serde_json::json!({
+                "access_token": "",
+                "token_type": "Bearer",
+                "refresh_token": refresh_token,
+            }))
+            .unwrap()
+        };
+        let mut session = claims();
+        session.bearer = bearer(Some("first"));
+
+        // a rotating provider returns a new refresh token, invalidating the old one
+        session.bearer = renewed_bearer(&session.bearer, bearer(Some("second")));
+        let session = JwtClaims::validate_with(&session.sign_with(&keys).unwrap(), &keys).unwrap();
+        assert_eq!(session.bearer.refresh_token.as_deref(), Some("second"));
+
+        // the next renewal sends the rotated one; a response without a refresh token keeps it
+        let mut session = session;
+        session.bearer = renewed_bearer(&session.bearer, bearer(None));
+        let session = JwtClaims::validate_with(&session.sign_with(&keys).unwrap(), &keys).unwrap();
+        assert_eq!(session.bearer.refresh_token.as_deref(), Some("second"));
+    }
+
+    #[test]
+    fn test_es256_round_trip() {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let private = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let public = PKey::public_key_from_pem(&private.public_key_to_pem().unwrap()).unwrap();
+        let verifier = |key| PKeyWithDigest {
+            digest: MessageDigest::sha256(),
+            key,
+        };
+        let keys = SessionKeys::Asymmetric {
+            signing: Some(PKeyWithDigest {
+                digest: MessageDigest::sha256(),
+                key: private,
+            }),
+            verifying: verifier(public.clone()),
+        };
+        let token = claims().sign_with(&keys).unwrap();
+        assert_eq!(
+            JwtClaims::validate_with(&token, &keys).unwrap().roles,
+            vec!["admin"]
+        );
+
+        let read_only = SessionKeys::Asymmetric {
+            signing: None,
+            verifying: verifier(public),
+        };
+        assert!(JwtClaims::validate_with(&token, &read_only).is_ok());
+        assert!(claims().sign_with(&read_only).is_err());
+
+        let hmac = hmac_keys(None, &[None]);
+        assert!(JwtClaims::validate_with(&token, &hmac).is_err());
     }
 }
@@ -1,31 +1,119 @@
-use std::io::Write;
+use std::io::{Read, Write};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use base64::{engine::general_purpose, Engine as _};
 use flate2::{
     write::{ZlibDecoder, ZlibEncoder},
     Compression,
 };
+use serde::{Deserialize, Serialize};
+
+use crate::config::CONFIG;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Algorithm {
+    None,
+    #[default]
+    Zlib,
+    Brotli,
+}
+
+/// First byte of an untagged zlib stream, as written before the format byte was introduced.
+const LEGACY_ZLIB: u8 = 0x78;
+
+impl Algorithm {
+    pub const ALL: &'static [Algorithm] = &[Algorithm::None, Algorithm::Zlib, Algorithm::Brotli];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::None => "none",
+            Algorithm::Zlib => "zlib",
+            Algorithm::Brotli => "brotli",
+        }
+    }
+
+    /// Format byte prepended to the compressed blob, so cookies written under a different `cookie_compression` still decode.
+    fn tag(&self) -> u8 {
+        match self {
+            Algorithm::None => b'r',
+            Algorithm::Zlib => b'z',
+            Algorithm::Brotli => b'b',
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        Self::ALL.iter().copied().find(|x| x.tag() == tag)
+    }
+}
 
 pub fn compress(jwt: &str) -> Result<String> {
+    compress_or_raw(jwt, CONFIG.cookie_compression)
+}
+
+/// Compressed with `algorithm`, unless the raw form is no larger, as with very short tokens where the compression overhead dominates.
+fn compress_or_raw(jwt: &str, algorithm: Algorithm) -> Result<String> {
+    let raw = compress_with(jwt, Algorithm::None)?;
+    if algorithm == Algorithm::None {
+        return Ok(raw);
+    }
+    let compressed = compress_with(jwt, algorithm)?;
+    Ok(if compressed.len() < raw.len() {
+        compressed
+    } else {
+        raw
+    })
+}
+
+pub fn compress_with(jwt: &str, algorithm: Algorithm) -> Result<String> {
     let components = jwt
         .trim()
         .split('.')
         .map(|x| Ok(general_purpose::URL_SAFE_NO_PAD.decode(x)?))
         .collect::<Result<Vec<_>>>()?;
     let body = components.join(&b"\n"[..]);
-    let mut encoder = ZlibEncoder::new(vec![], Compression::best());
-    encoder.write_all(&body)?;
-    let compressed_bytes = encoder.finish()?;
+    let mut compressed_bytes = vec![algorithm.tag()];
+    match algorithm {
+        Algorithm::None => compressed_bytes.extend(body),
+        Algorithm::Zlib => {
+            let mut encoder = ZlibEncoder::new(compressed_bytes, Compression::best());
+            encoder.write_all(&body)?;
+            compressed_bytes = encoder.finish()?;
+        }
+        Algorithm::Brotli => {
+            let mut encoder = brotli::CompressorWriter::new(compressed_bytes, 4096, 11, 22);
+            encoder.write_all(&body)?;
+            compressed_bytes = encoder.into_inner();
+        }
+    }
 
     Ok(general_purpose::URL_SAFE_NO_PAD.encode(&compressed_bytes))
 }
 
+/// Inverse of `compress_with` for any algorithm, detected from the format byte.
 pub fn decompress(jwt: &str) -> Result<String> {
     let compressed = general_purpose::URL_SAFE_NO_PAD.decode(jwt.trim())?;
-    let mut decoder = ZlibDecoder::new(vec![]);
-    decoder.write_all(&compressed)?;
-    let decompressed = decoder.finish()?;
+    let (algorithm, compressed) = match compressed.split_first() {
+        Some((&LEGACY_ZLIB, _)) => (Algorithm::Zlib, &compressed[..]),
+        Some((tag, rest)) => match Algorithm::from_tag(*tag) {
+            Some(algorithm) => (algorithm, rest),
+            None => bail!("unknown compression format {tag:#04x}"),
+        },
+        None => bail!("empty session value"),
+    };
+    let decompressed = match algorithm {
+        Algorithm::None => compressed.to_vec(),
+        Algorithm::Zlib => {
+            let mut decoder = ZlibDecoder::new(vec![]);
+            decoder.write_all(compressed)?;
+            decoder.finish()?
+        }
+        Algorithm::Brotli => {
+            let mut out = vec![];
+            brotli::Decompressor::new(compressed, 4096).read_to_end(&mut out)?;
+            out
+        }
+    };
     Ok(decompressed
         .splitn(3, |x| *x == b'\n')
         .map(|x| general_purpose::URL_SAFE_NO_PAD.encode(&x))
@@ -40,8 +128,41 @@ mod tests {
 
     #[test]
     fn test_jwtc() {
-        let compressed = compress(TEST_TOKEN).unwrap();
-        println!("token size = {}", compressed.len());
-        assert_eq!(TEST_TOKEN, decompress(&compressed).unwrap());
+        for algorithm in Algorithm::ALL {
+            let compressed = compress_with(TEST_TOKEN, *algorithm).unwrap();
+            println!("{} token size = {}", algorithm.name(), compressed.len());
+            assert_eq!(TEST_TOKEN, decompress(&compressed).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_raw_fallback() {
+        let tiny = "e30.e30.AA";
+        for algorithm in Algorithm::ALL {
+            let encoded = compress_or_raw(tiny, *algorithm).unwrap();
+            assert_eq!(encoded, compress_with(tiny, Algorithm::None).unwrap());
+            assert_eq!(tiny, decompress(&encoded).unwrap());
+        }
+
+        let large = format!("{TEST_TOKEN}{}", "A".repeat(2000));
+        let encoded = compress_or_raw(&large, Algorithm::Zlib).unwrap();
+        assert_eq!(encoded, compress_with(&large, Algorithm::Zlib).unwrap());
+    }
+
+    #[test]
+    fn test_legacy_zlib() {
+        // written before the format byte: a bare zlib stream
+        let body = TEST_TOKEN
+            .split('.')
+            .map(|x| general_purpose::URL_SAFE_NO_PAD.decode(x).unwrap())
+            .collect::<Vec<_>>()
+            .join(&b"\n"[..]);
+        let mut encoder = ZlibEncoder::new(vec![], Compression::best());
+        encoder.write_all(&body).unwrap();
+        let legacy = general_purpose::URL_SAFE_NO_PAD.encode(encoder.finish().unwrap());
+        assert_eq!(TEST_TOKEN, decompress(&legacy).unwrap());
+
+        let unknown = general_purpose::URL_SAFE_NO_PAD.encode(b"?garbage");
+        assert!(decompress(&unknown).is_err());
     }
 }
@@ -1,18 +1,87 @@
-use axol::Query;
+use axol::{ConnectInfo, Error, Query, Result, Typed};
+use axol_http::{header::HeaderMap, typed_headers::Cookie as CookieHeader};
 use serde::Deserialize;
+use tracing::warn;
 use url::Url;
 
-use crate::{config::REDIRECT_URL, oidc::OIDC};
+use crate::{
+    auth::cookie_secure,
+    config::{live, CONFIG, LOGIN_URL, REDIRECT_URL},
+    flow::FlowState,
+    oidc::provider,
+    validate::{session_claims, session_state, SessionState},
+};
 
 #[derive(Deserialize)]
 pub struct LoginParameters {
     url: Url,
+    /// Set by `/auth` when it restarts a login after the IdP rejected the code.
+    #[serde(default)]
+    retry: bool,
 }
 
-pub async fn login(Query(query): Query<LoginParameters>) -> Url {
+/// `/login` url that eventually redirects back to `url`.
+pub fn login_url(url: &Url) -> Url {
+    let mut login = LOGIN_URL.clone();
+    login.query_pairs_mut().append_pair("url", url.as_str());
+    login
+}
+
+/// `login_url` for restarting a login whose code was rejected, which is only retried once.
+pub fn retry_login_url(url: &Url) -> Url {
+    let mut login = login_url(url);
+    login.query_pairs_mut().append_pair("retry", "true");
+    login
+}
+
+pub async fn login(
+    Query(query): Query<LoginParameters>,
+    cookies: Option<Typed<CookieHeader>>,
+    headers_in: HeaderMap,
+    connect_info: ConnectInfo,
+) -> Result<(HeaderMap, Url)> {
+    if !CONFIG.is_redirect_allowed(&query.url) {
+        warn!(url = %query.url, "redirect not allowed");
+        return Err(Error::bad_request("redirect not allowed"));
+    }
+    let live = live();
+    let customized = live.customized_url(&query.url, Some("GET"), connect_info.ip());
+
+    if CONFIG.login_reuse_session {
+        if let Ok(claims) =
+            session_claims(cookies.as_ref().map(|x| &x.0), customized.accept_any_issuer)
+        {
+            let same_client = claims.client_id.as_deref()
+                == customized.client.map(|x| &*x.client_id)
+                && claims.provider.as_deref() == CONFIG.provider_for_url(&query.url);
+            if same_client && matches!(session_state(&claims, &customized), SessionState::Live) {
+                return Ok((HeaderMap::new(), query.url));
+            }
+        }
+    }
+
     let mut redirect_uri = REDIRECT_URL.clone();
     redirect_uri
         .query_pairs_mut()
         .append_pair("url", query.url.as_str());
-    OIDC.auth_url(redirect_uri).await
+    let oidc = provider(CONFIG.provider_for_url(&query.url));
+    let (flow, state) = FlowState::new(query.url, query.retry);
+    let url = oidc
+        .auth_url(
+            redirect_uri,
+            &state,
+            &flow.nonce,
+            &flow.challenge(),
+            customized.client,
+        )
+        .await;
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "set-cookie",
+        flow.cookie(cookie_secure(&headers_in))
+            .map_err(Error::internal)?
+            .encoded()
+            .to_string(),
+    );
+    Ok((headers, url))
 }
@@ -0,0 +1,148 @@
+use axol::{Error, Query, Result, Typed};
+use axol_http::{header::HeaderMap, typed_headers::Cookie as CookieHeader};
+use serde::Deserialize;
+use url::Url;
+
+use crate::{
+    auth::{clear_cookies, cookie_names, cookie_secure},
+    config::CONFIG,
+    oidc::provider,
+    revocation::REVOCATIONS,
+    validate::session_claims,
+};
+
+#[derive(Deserialize)]
+pub struct LogoutParameters {
+    url: Option<Url>,
+}
+
+/// What the session can tell the provider about whom to log out.
+#[derive(Default)]
+struct LogoutHint<'a> {
+    id_token: Option<&'a str>,
+    sub: Option<&'a str>,
+    sid: Option<&'a str>,
+}
+
+/// Where to send the browser after clearing the session: the provider's RP-initiated logout if it has one, otherwise straight to `url`.
+/// Without an id_token, the subject and IdP session id are sent as `logout_hint` and `sid` instead.
+fn logout_redirect(
+    end_session_endpoint: Option<&Url>,
+    url: Url,
+    client_id: &str,
+    hint: LogoutHint<'_>,
+) -> Url {
+    let Some(end_session_endpoint) = end_session_endpoint else {
+        return url;
+    };
+    let mut redirect = end_session_endpoint.clone();
+    {
+        let mut pairs = redirect.query_pairs_mut();
+        pairs
+            .append_pair("post_logout_redirect_uri", url.as_str())
+            .append_pair("client_id", client_id);
+        if let Some(id_token) = hint.id_token {
+            pairs.append_pair("id_token_hint", id_token);
+        } else {
+            if let Some(sub) = hint.sub {
+                pairs.append_pair("logout_hint", sub);
+            }
+            if let Some(sid) = hint.sid {
+                pairs.append_pair("sid", sid);
+            }
+        }
+    }
+    redirect
+}
+
+pub async fn logout(
+    Query(query): Query<LogoutParameters>,
+    cookies: Option<Typed<CookieHeader>>,
+    headers_in: HeaderMap,
+) -> Result<(HeaderMap, Url)> {
+    if let Some(url) = &query.url {
+        if !CONFIG.is_redirect_allowed(url) {
+            return Err(Error::bad_request("redirect not allowed"));
+        }
+    }
+    let session = session_claims(cookies.as_ref().map(|x| &x.0), false).ok();
+    if let Some(session) = session.as_ref().filter(|_| CONFIG.session_revocation) {
+        if let Some(jti) = &session.jti {
+            REVOCATIONS.revoke_session(jti, session.exp);
+        }
+    }
+    let url = query.url.unwrap_or_else(|| CONFIG.public.clone());
+    let oidc = provider(session.as_ref().and_then(|x| x.provider.as_deref()));
+    let client_id = session
+        .as_ref()
+        .and_then(|x| x.client_id.as_deref())
+        .unwrap_or(&oidc.provider.client_id);
+    let hint = session
+        .as_ref()
+        .map(|x| LogoutHint {
+            id_token: x.bearer.id_token.as_deref(),
+            sub: x.sub.as_deref(),
+            sid: x.sid.as_deref(),
+        })
+        .unwrap_or_default();
+
+    let end_session_endpoint = oidc.end_session_endpoint().await;
+    let redirect = logout_redirect(end_session_endpoint.as_ref(), url, client_id, hint);
+
+    let mut headers = HeaderMap::new();
+    let present = cookie_names(cookies.as_ref().map(|x| &x.0));
+    for cookie in clear_cookies(&present, cookie_secure(&headers_in)) {
+        headers.append("set-cookie", cookie.encoded().to_string());
+    }
+    Ok((headers, redirect))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_logout_redirect() {
+        let url = Url::parse("https://app.my.domain/bye").unwrap();
+        let end_session = Url::parse("https://idp.my.domain/logout?realm=x").unwrap();
+
+        let with_id_token = || LogoutHint {
+            id_token: Some("hint"),
+            sub: Some("user"),
+            sid: Some("session"),
+        };
+        assert_eq!(
+            logout_redirect(None, url.clone(), "my_client_id", with_id_token()),
+            url
+        );
+
+        let redirect = logout_redirect(
+            Some(&end_session),
+            url.clone(),
+            "my_client_id",
+            LogoutHint::default(),
+        );
+        assert_eq!(
+            redirect.as_str(),
+            "https://idp.my.domain/logout?realm=x&post_logout_redirect_uri=https%3A%2F%2Fapp.my.domain%2Fbye&client_id=my_client_id"
+        );
+
+        let redirect = logout_redirect(
+            Some(&end_session),
+            url.clone(),
+            "my_client_id",
+            with_id_token(),
+        );
+        assert!(redirect.as_str().ends_with("&id_token_hint=hint"));
+
+        // the id_token was stripped from the session
+        let hint = LogoutHint {
+            id_token: None,
+            ..with_id_token()
+        };
+        let redirect = logout_redirect(Some(&end_session), url, "my_client_id", hint);
+        assert!(redirect
+            .as_str()
+            .ends_with("&client_id=my_client_id&logout_hint=user&sid=session"));
+    }
+}
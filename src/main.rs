@@ -1,51 +1,127 @@
 use std::time::Duration;
 
-use axol::cors::{Any, Cors};
+use axol::cors::{AllowMethods, AllowOrigin, Any, Cors};
 use axol::trace::RegistryWrapper;
 use axol::{trace::Trace, Router};
 use axol::{Logger, RealIp};
-use axol_http::response::Response;
-use config::{CONFIG, PUBLIC_URL_BASE};
+use axol_http::{response::Response, StatusCode};
+use config::{mounted_path, LogFormat, CONFIG, ROUTE_BASE};
 use opentelemetry::runtime::Tokio;
 use opentelemetry::sdk::propagation::TraceContextPropagator;
 use opentelemetry_otlp::{ExportConfig, Protocol, WithExportConfig};
-use tracing::{error, info, span, Instrument};
+use tracing::{error, info, span, warn, Instrument, Subscriber};
 use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::Registry;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{EnvFilter, Layer, Registry};
 
+mod bench;
+mod chunks;
+mod claims;
 mod config;
+mod flow;
 mod jwt;
 mod jwtc;
+mod metrics;
 mod oidc;
+mod response;
+mod revocation;
+mod seal;
 
 mod auth;
 mod login;
+mod logout;
+mod revoke;
 mod validate;
+mod validate_token;
 
-async fn health() {}
+/// Liveness: the process is up and serving.
+async fn health_live() {}
 
+/// Readiness: OIDC is usable, judged from the last discovery rather than probing the provider.
+async fn health_ready() -> axol::Result<()> {
+    if !oidc::ready() {
+        return Err(response::status_error(StatusCode::ServiceUnavailable));
+    }
+    Ok(())
+}
+
+/// Default for every response that a route-specific `cache_control` entry didn't already cover.
 async fn cache_control(mut response: Response) -> Response {
+    if response.headers.get("cache-control").is_none() {
+        response
+            .headers
+            .insert("cache-control", "no-store, must-revalidate, max-age=0");
+    }
     response
-        .headers
-        .insert("cache-control", "no-store, must-revalidate, max-age=0");
+}
+
+async fn route_cache_control(value: &'static str, mut response: Response) -> Response {
+    response.headers.insert("cache-control", value);
     response
 }
 
+/// The configured CORS policy. Allowing any origin by default is justified in that the only route exercising the session's privileges, /revoke, requires a same-origin request.
+/// The /login endpoint could be used a redirect loop, but in practice is this avoided from redirect whitelists on the side of the OIDC provider.
+fn cors() -> Cors {
+    let config = &CONFIG.cors;
+    let origins = if config.any_origin() {
+        AllowOrigin::from(Any)
+    } else {
+        AllowOrigin::list(config.allow_origins.iter().cloned())
+    };
+    let methods = if config.any_method() {
+        AllowMethods::from(Any)
+    } else {
+        AllowMethods::list(config.allow_methods.iter().cloned())
+    };
+    let mut cors = Cors::default()
+        .allow_origin(origins)
+        .allow_methods(methods)
+        .allow_credentials(config.allow_credentials);
+    if let Some(max_age) = config.max_age_sec {
+        cors = cors.max_age(Duration::from_secs(max_age));
+    }
+    cors
+}
+
+/// Routes nested under `ROUTE_BASE`, kept in sync with `route` for the startup log.
+const ROUTES: &[&str] = &[
+    "/validate",
+    "/validate-token",
+    "/login",
+    "/logout",
+    "/auth",
+    "/revoke",
+    "/health",
+    "/health/live",
+    "/health/ready",
+];
+
 fn route(registry: Option<RegistryWrapper>) -> Router {
-    Router::default()
+    let mut router = Router::default();
+    for (route, value) in &CONFIG.cache_control {
+        router = router.late_response_hook(
+            &mounted_path(&ROUTE_BASE, route),
+            move |response: Response| route_cache_control(value, response),
+        );
+    }
+    router
         .nest(
-            &*PUBLIC_URL_BASE,
+            &*ROUTE_BASE,
             Router::new()
                 .get("/validate", validate::validate)
+                .get("/validate-token", validate_token::validate_token)
                 .get("/login", login::login)
+                .get("/logout", logout::logout)
                 .get("/auth", auth::auth)
-                .get("/health", health),
+                .post("/revoke", revoke::revoke)
+                .get("/health", health_live)
+                .get("/health/live", health_live)
+                .get("/health/ready", health_ready),
         )
         .request_hook_direct("/", RealIp("x-original-forwarded-for".to_string()))
         .late_response_hook("/", cache_control)
-        // allow origin * is justified in that each route does not perform stateful action.
-        // the /login endpoint could be used a redirect loop, but in practice is this avoided from redirect whitelists on the side of the OIDC provider
-        .plugin("/", Cors::default().allow_methods(Any).allow_origin("*"))
+        .plugin("/", cors())
         .plugin(
             "/",
             registry
@@ -56,6 +132,7 @@ fn route(registry: Option<RegistryWrapper>) -> Router {
 }
 
 async fn run(registry: Option<RegistryWrapper>) {
+    tokio::spawn(config::reload_on_sighup());
     info!("initializing OIDC...");
     oidc::init().await;
     info!("OIDC initialized");
@@ -65,12 +142,56 @@ async fn run(registry: Option<RegistryWrapper>) {
         .router(route(registry))
         .serve();
     info!("listening on {}", CONFIG.bind);
+    for route in ROUTES {
+        info!("mounted {}", mounted_path(&ROUTE_BASE, route));
+    }
+
+    let (shutdown, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+    let server = server.with_graceful_shutdown(async move {
+        shutdown_rx.await.ok();
+    });
+    let drain = async move {
+        shutdown_signal().await;
+        info!("shutting down, draining in-flight requests");
+        shutdown.send(()).ok();
+        tokio::time::sleep(Duration::from_secs(CONFIG.shutdown_timeout_sec)).await;
+    };
+    tokio::select! {
+        result = server => if let Err(e) = result {
+            error!("server error: {}", e);
+        },
+        _ = drain => warn!("requests still in flight after shutdown_timeout_sec, exiting anyway"),
+    }
+}
 
-    if let Err(e) = server.await {
-        error!("server error: {}", e);
+/// Resolves on SIGINT or SIGTERM, the latter being how Kubernetes stops pods.
+async fn shutdown_signal() {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = terminate.recv() => (),
     }
 }
 
+/// One JSON object per event, including the fields of the enclosing spans. Filtered by `RUST_LOG` like the default logger.
+fn json_log_layer<S>() -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt::layer().json().with_filter(filter)
+}
+
+/// Human readable events, for the messages logged before `env_logger` takes over.
+fn text_log_layer<S>() -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt::layer().with_filter(filter)
+}
+
 lazy_static::lazy_static! {
     pub(crate) static ref REGISTRY: RegistryWrapper = {
         RegistryWrapper::from(Registry::default())
@@ -79,12 +200,39 @@ lazy_static::lazy_static! {
 
 #[tokio::main]
 async fn main() {
-    env_logger::Builder::new()
-        .parse_env(env_logger::Env::default().default_filter_or("info"))
-        .init();
-    lazy_static::initialize(&CONFIG);
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.get(1).map(|x| &**x) == Some("bench-compression") {
+        let token = args
+            .get(2)
+            .expect("usage: oiplease bench-compression <sample-token>");
+        if let Err(e) = bench::compression(token) {
+            eprintln!("benchmark failed: {e:#}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let env_format = std::env::var("OIPLEASE_LOG_FORMAT").ok();
+    // config validation logs before the global subscriber exists, and before `log_format` is known
+    let early = if env_format.as_deref() == Some("json") {
+        json_log_layer().boxed()
+    } else {
+        text_log_layer().boxed()
+    };
+    tracing::subscriber::with_default(Registry::default().with(early), || {
+        lazy_static::initialize(&CONFIG)
+    });
+    let json_logs = match env_format.as_deref() {
+        Some(format) => format == "json",
+        None => CONFIG.log_format == LogFormat::Json,
+    };
+    if !json_logs {
+        env_logger::Builder::new()
+            .parse_env(env_logger::Env::default().default_filter_or("info"))
+            .init();
+    }
 
-    let registry = if let Some(config) = &CONFIG.opentelemetry {
+    let telemetry = if let Some(config) = &CONFIG.opentelemetry {
         let tracer = opentelemetry_otlp::new_pipeline()
             .tracing()
             .with_exporter(
@@ -99,12 +247,21 @@ async fn main() {
             .install_batch(Tokio)
             .expect("tracer init failed");
 
-        let telemetry = tracing_opentelemetry::layer().with_tracer(tracer);
-
-        tracing::subscriber::set_global_default(REGISTRY.clone().with(telemetry)).unwrap();
         opentelemetry::global::set_text_map_propagator(TraceContextPropagator::default());
-        info!("otel tracing initialized");
-        Some(REGISTRY.clone())
+        Some(tracing_opentelemetry::layer().with_tracer(tracer))
+    } else {
+        None
+    };
+    let registry = if telemetry.is_some() || json_logs {
+        let json = json_logs.then(json_log_layer);
+        tracing::subscriber::set_global_default(REGISTRY.clone().with(telemetry).with(json))
+            .unwrap();
+        info!(
+            json_logs,
+            otel = CONFIG.opentelemetry.is_some(),
+            "tracing initialized"
+        );
+        CONFIG.opentelemetry.as_ref().map(|_| REGISTRY.clone())
     } else {
         None
     };
@@ -116,4 +273,11 @@ async fn main() {
     let root = span!(tracing::Level::INFO, "app_start");
 
     run(registry).instrument(root).await;
+
+    if CONFIG.opentelemetry.is_some() {
+        // flushes the batch exporter, blocking, so off the async workers
+        tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider)
+            .await
+            .ok();
+    }
 }
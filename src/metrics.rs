@@ -0,0 +1,60 @@
+use prometheus::{
+    register_histogram, register_int_counter, register_int_counter_vec, register_int_gauge,
+    Histogram, IntCounter, IntCounterVec, IntGauge,
+};
+
+lazy_static::lazy_static! {
+    pub static ref OVERSIZED_COOKIES: IntCounter = register_int_counter!(
+        "oiplease_oversized_cookies_total",
+        "Logins or renewals rejected because the session exceeded max_total_cookie_bytes"
+    )
+    .unwrap();
+    pub static ref OIDC_LAST_DISCOVERY: IntGauge = register_int_gauge!(
+        "oiplease_oidc_last_discovery_timestamp_seconds",
+        "When OIDC discovery last succeeded"
+    )
+    .unwrap();
+    pub static ref OIDC_NEXT_REFRESH: IntGauge = register_int_gauge!(
+        "oiplease_oidc_next_refresh_timestamp_seconds",
+        "When the OIDC client is next rediscovered"
+    )
+    .unwrap();
+    pub static ref OIDC_JWKS_KEYS: IntGauge = register_int_gauge!(
+        "oiplease_oidc_jwks_keys",
+        "Number of keys in the IdP's JWKS"
+    )
+    .unwrap();
+    pub static ref OIDC_RECONNECTING: IntGauge = register_int_gauge!(
+        "oiplease_oidc_reconnecting",
+        "1 while OIDC discovery is being retried"
+    )
+    .unwrap();
+    pub static ref CONFIG_RELOADS: IntCounterVec = register_int_counter_vec!(
+        "oiplease_config_reloads_total",
+        "SIGHUP config reloads: applied, or rejected if the file failed to load or validate",
+        &["outcome"]
+    )
+    .unwrap();
+    pub static ref CONFIG_GENERATION: IntGauge = register_int_gauge!(
+        "oiplease_config_generation",
+        "Number of config reloads applied since startup"
+    )
+    .unwrap();
+    /// `hostname` is only ever a host named by a customization, or `other`, to bound cardinality.
+    pub static ref VALIDATE_OUTCOMES: IntCounterVec = register_int_counter_vec!(
+        "oiplease_validate_outcomes_total",
+        "/validate decisions: pass, renewed, expired, forbidden, unauthorized, bypass or error",
+        &["outcome", "hostname"]
+    )
+    .unwrap();
+    pub static ref OIDC_TOKEN_EXCHANGE_SECONDS: Histogram = register_histogram!(
+        "oiplease_oidc_token_exchange_seconds",
+        "Latency of authorization code exchanges at the token endpoint"
+    )
+    .unwrap();
+    pub static ref OIDC_REFRESH_SECONDS: Histogram = register_histogram!(
+        "oiplease_oidc_refresh_seconds",
+        "Latency of refresh token grants at the token endpoint"
+    )
+    .unwrap();
+}
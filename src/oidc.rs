@@ -2,49 +2,143 @@ use always_cell::AlwaysCell;
 use anyhow::{bail, Context, Result};
 use axol::trace::{default_request_header_filter, http_flavor};
 use axol_http::typed_headers::HeaderMap;
+use base64::{engine::general_purpose, Engine as _};
 use chrono::{DateTime, Utc};
 use openid::{
-    Bearer, Client, CompactJson, CustomClaims, Discovered, Options, StandardClaims, Token,
+    biscuit::{jwa::SignatureAlgorithm, jwk::JWKSet},
+    error::{ClientError, OAuth2Error, OAuth2ErrorCode},
+    Bearer, Client, CompactJson, CustomClaims, Discovered, IdToken, Options, StandardClaims, Token,
 };
 use opentelemetry::{Key, StringValue, Value};
 use reqwest_maybe_middleware::Extensions;
 use reqwest_tracing::{ReqwestOtelSpanBackend, TracingMiddleware};
 use serde::{Deserialize, Serialize};
-use std::{sync::Arc, time::Duration};
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use tokio::sync::RwLock;
-use tracing::{field::Empty, warn, Instrument};
+use tracing::{field::Empty, info, warn, Instrument};
 use tracing_opentelemetry::OtelData;
 use tracing_subscriber::registry::{LookupSpan, SpanData};
 use url::Url;
 
 use crate::{
-    config::{CONFIG, REDIRECT_URL},
+    claims::claim_strings,
+    config::{ClientRegistration, ProviderConfig, CONFIG, REDIRECT_URL},
+    metrics::{
+        OIDC_JWKS_KEYS, OIDC_LAST_DISCOVERY, OIDC_NEXT_REFRESH, OIDC_RECONNECTING,
+        OIDC_REFRESH_SECONDS, OIDC_TOKEN_EXCHANGE_SECONDS,
+    },
     REGISTRY,
 };
 
+/// Where and as whom a handler authenticates: the top-level config, or an entry of `providers`.
+pub struct Provider {
+    /// `None` for the top-level provider, which alone reports to the OIDC metrics.
+    pub name: Option<String>,
+    pub issuer: Url,
+    pub client_id: String,
+    pub client_secret: String,
+    pub scopes: String,
+}
+
+impl Provider {
+    fn primary() -> Self {
+        Provider {
+            name: None,
+            issuer: CONFIG.issuer.clone(),
+            client_id: CONFIG.client_id.clone(),
+            client_secret: CONFIG.client_secret.clone(),
+            scopes: CONFIG.scopes.clone(),
+        }
+    }
+
+    fn named(name: &str, config: &ProviderConfig) -> Self {
+        Provider {
+            name: Some(name.to_string()),
+            issuer: config.issuer.clone(),
+            client_id: config.client_id.clone(),
+            client_secret: config.client_secret.clone(),
+            scopes: config.scopes.clone(),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct OidcHandler {
     client: Arc<RwLock<(DateTime<Utc>, Client<Discovered, Claims>)>>,
+    pub provider: Arc<Provider>,
+    /// Unix time of the last successful discovery, or of the cached one in use.
+    discovered_at: Arc<AtomicI64>,
+    /// While serving a cached discovery, when it gets too old to use. `i64::MAX` once discovered live.
+    cached_until: Arc<AtomicI64>,
 }
 
-lazy_static::lazy_static! {
-    static ref OIDC_OPTIONS: Options = Options {
-        scope: Some(CONFIG.scopes.clone()),
-        state: None,
-        ..Default::default()
-    };
-}
-pub static OIDC: AlwaysCell<OidcHandler> = AlwaysCell::new();
+static OIDC: AlwaysCell<OidcHandler> = AlwaysCell::new();
+static PROVIDERS: AlwaysCell<HashMap<String, OidcHandler>> = AlwaysCell::new();
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
 
+/// Discovers every provider concurrently, so an unreachable one only delays startup by itself rather than each after it.
 pub async fn init() {
-    AlwaysCell::set(&OIDC, OidcHandler::new().await);
+    let named = CONFIG
+        .providers
+        .iter()
+        .map(|(name, config)| {
+            let provider = Provider::named(name, config);
+            (name.clone(), tokio::spawn(OidcHandler::new(provider)))
+        })
+        .collect::<Vec<_>>();
+    AlwaysCell::set(&OIDC, OidcHandler::new(Provider::primary()).await);
+    let mut providers = HashMap::new();
+    for (name, handler) in named {
+        let handler = handler.await.expect("provider discovery panicked");
+        providers.insert(name, handler);
+    }
+    AlwaysCell::set(&PROVIDERS, providers);
+    for handler in handlers() {
+        handler.spawn_refresh_loop();
+    }
+    INITIALIZED.store(true, Ordering::Release);
+}
+
+fn handlers() -> impl Iterator<Item = &'static OidcHandler> {
+    std::iter::once(&*OIDC).chain(PROVIDERS.values())
+}
+
+/// Whether every provider is initialized and was discovered within `ready_max_discovery_age_sec`. Never touches the network.
+pub fn ready() -> bool {
+    if !INITIALIZED.load(Ordering::Acquire) {
+        return false;
+    }
+    let max_age = CONFIG
+        .ready_max_discovery_age_sec
+        .unwrap_or(3 * CONFIG.oidc_refresh_time_sec as i64);
+    let now = Utc::now().timestamp();
+    handlers().all(|x| now - x.discovered_at.load(Ordering::Relaxed) <= max_age)
+}
+
+/// The handler for the named entry of `providers`, or the top-level provider for `None`.
+pub fn provider(name: Option<&str>) -> &'static OidcHandler {
+    name.and_then(|x| PROVIDERS.get(x)).unwrap_or(&*OIDC)
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Claims {
     pub realm_access: Option<RealmAccess>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub resource_access: HashMap<String, RealmAccess>,
     #[serde(flatten)]
     pub standard: StandardClaims,
+    /// Non-standard claims, so roles and required claims can be read from arbitrary paths.
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -61,6 +155,45 @@ impl CustomClaims for Claims {
 
 impl CompactJson for Claims {}
 
+/// OAuth `error` response from the token endpoint.
+pub struct TokenEndpointError {
+    pub error: String,
+    pub description: Option<String>,
+}
+
+impl TokenEndpointError {
+    /// Finds the token endpoint's OAuth error in an error chain from `validate_code` or `renew`.
+    pub fn find(e: &anyhow::Error) -> Option<Self> {
+        e.chain()
+            .find_map(|cause| match cause.downcast_ref::<ClientError>()? {
+                ClientError::OAuth2(e) => Some(TokenEndpointError {
+                    error: match &e.error {
+                        OAuth2ErrorCode::InvalidRequest => "invalid_request",
+                        OAuth2ErrorCode::InvalidClient => "invalid_client",
+                        OAuth2ErrorCode::InvalidGrant => "invalid_grant",
+                        OAuth2ErrorCode::UnauthorizedClient => "unauthorized_client",
+                        OAuth2ErrorCode::UnsupportedGrantType => "unsupported_grant_type",
+                        OAuth2ErrorCode::InvalidScope => "invalid_scope",
+                        OAuth2ErrorCode::Unrecognized(x) => x,
+                    }
+                    .to_string(),
+                    description: e.error_description.clone(),
+                }),
+                _ => None,
+            })
+    }
+
+    /// `invalid_grant`: the code or refresh token is stale, the user must log in again.
+    pub fn is_invalid_grant(&self) -> bool {
+        self.error == "invalid_grant"
+    }
+
+    /// `invalid_client`: our client credentials are rejected, so the config is broken.
+    pub fn is_invalid_client(&self) -> bool {
+        self.error == "invalid_client" || self.error == "unauthorized_client"
+    }
+}
+
 lazy_static::lazy_static! {
     static ref HTTP_CLIENT: reqwest_maybe_middleware::Client = reqwest_middleware::ClientBuilder::new(reqwest::Client::new())
         .with(TracingMiddleware::<Tracer>::new()).build().into();
@@ -191,54 +324,270 @@ impl ReqwestOtelSpanBackend for Tracer {
     }
 }
 
+/// Last-known-good discovery, so a restart doesn't wait on an unavailable IdP.
+#[derive(Serialize, Deserialize)]
+struct DiscoveryCache {
+    issuer: Url,
+    fetched_at: i64,
+    config: openid::Config,
+    jwks: Option<JWKSet<openid::biscuit::Empty>>,
+}
+
+impl DiscoveryCache {
+    /// The cache in `raw`, unless it was written for another issuer or is older than `max_age` at `now`.
+    fn parse(raw: &[u8], issuer: &Url, max_age: i64, now: i64) -> serde_json::Result<Option<Self>> {
+        let cache: DiscoveryCache = serde_json::from_slice(raw)?;
+        Ok((&cache.issuer == issuer && now - cache.fetched_at <= max_age).then_some(cache))
+    }
+}
+
+impl Provider {
+    fn cache_path(&self) -> Option<PathBuf> {
+        let dir = CONFIG.discovery_cache_dir.as_ref()?;
+        Some(dir.join(format!(
+            "{}.json",
+            self.name.as_deref().unwrap_or("default")
+        )))
+    }
+
+    async fn load_cache(&self) -> Option<(i64, Client<Discovered, Claims>)> {
+        let path = self.cache_path()?;
+        let raw = tokio::fs::read(&path).await.ok()?;
+        let now = Utc::now().timestamp();
+        let cache = match DiscoveryCache::parse(
+            &raw,
+            &self.issuer,
+            CONFIG.discovery_cache_max_age_sec,
+            now,
+        ) {
+            Ok(x) => x?,
+            Err(e) => {
+                warn!(path = %path.display(), "ignoring unreadable discovery cache: {e}");
+                return None;
+            }
+        };
+        let age = now - cache.fetched_at;
+        info!(provider = ?self.name, "using discovery cached {age}s ago");
+        let client = Client::new(
+            Discovered(cache.config),
+            self.client_id.clone(),
+            self.client_secret.clone(),
+            Some(REDIRECT_URL.to_string()),
+            HTTP_CLIENT.clone(),
+            cache.jwks,
+        );
+        Some((cache.fetched_at, client))
+    }
+
+    async fn save_cache(&self, client: &Client<Discovered, Claims>) {
+        let Some(path) = self.cache_path() else {
+            return;
+        };
+        let cache = DiscoveryCache {
+            issuer: self.issuer.clone(),
+            fetched_at: Utc::now().timestamp(),
+            config: client.config().clone(),
+            jwks: client.jwks.clone(),
+        };
+        let result = match serde_json::to_vec(&cache) {
+            Ok(raw) => tokio::fs::write(&path, raw)
+                .await
+                .map_err(anyhow::Error::from),
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            warn!(path = %path.display(), "failed to write discovery cache: {e}");
+        }
+    }
+}
+
 impl OidcHandler {
-    async fn new() -> Self {
-        let client = Self::recreate().await;
+    async fn new(provider: Provider) -> Self {
+        let cached = provider.load_cache().await;
+        // a cached start is due for refresh right away, which the refresh loop picks up as its first deadline
+        let next_refresh = match &cached {
+            Some(_) => Utc::now(),
+            None => refresh_deadline(Utc::now()),
+        };
+        let cached_until = match &cached {
+            Some((fetched_at, _)) => fetched_at + CONFIG.discovery_cache_max_age_sec,
+            None => i64::MAX,
+        };
+        let (discovered_at, client) = match cached {
+            Some(cached) => cached,
+            None => {
+                let client = Self::recreate(&provider).await;
+                (Utc::now().timestamp(), client)
+            }
+        };
+        if provider.name.is_none() {
+            OIDC_NEXT_REFRESH.set(next_refresh.timestamp());
+        }
         Self {
-            client: Arc::new(RwLock::new((
-                Utc::now() + chrono::Duration::seconds(CONFIG.oidc_refresh_time_sec as i64),
-                client,
-            ))),
+            client: Arc::new(RwLock::new((next_refresh, client))),
+            provider: Arc::new(provider),
+            discovered_at: Arc::new(AtomicI64::new(discovered_at)),
+            cached_until: Arc::new(AtomicI64::new(cached_until)),
         }
     }
 
-    async fn recreate() -> Client<Discovered, Claims> {
+    /// Rediscovers without holding the lock, so requests keep using the previous client meanwhile, then swaps the new one in.
+    async fn refresh(&self) {
+        let client = Self::recreate(&self.provider).await;
+        let next_refresh = refresh_deadline(Utc::now());
+        if self.provider.name.is_none() {
+            OIDC_NEXT_REFRESH.set(next_refresh.timestamp());
+        }
+        *self.client.write().await = (next_refresh, client);
+        self.discovered_at
+            .store(Utc::now().timestamp(), Ordering::Relaxed);
+        self.cached_until.store(i64::MAX, Ordering::Relaxed);
+    }
+
+    /// Fails once a cached discovery outlives `discovery_cache_max_age_sec` without the provider coming back,
+    /// rather than trusting its endpoints and keys indefinitely.
+    fn check_cache_age(&self) -> Result<()> {
+        if Utc::now().timestamp() > self.cached_until.load(Ordering::Relaxed) {
+            bail!("cached discovery expired and the provider is still unreachable");
+        }
+        Ok(())
+    }
+
+    /// Refreshes ahead of each deadline, so requests never wait on discovery. While the provider is unreachable
+    /// `discover` keeps retrying and requests keep using the previous client. It never panics.
+    /// This is the only caller of `refresh`, so a provider is never rediscovered twice at once.
+    fn spawn_refresh_loop(&self) {
+        let handler = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let deadline = handler.client.read().await.0;
+                let lead =
+                    chrono::Duration::seconds((CONFIG.oidc_refresh_time_sec / 10).min(60) as i64);
+                let wait = (deadline - lead - Utc::now()).to_std().unwrap_or_default();
+                tokio::time::sleep(wait).await;
+                handler
+                    .refresh()
+                    .instrument(
+                        tracing::debug_span!("OIDC refresh", provider = ?handler.provider.name),
+                    )
+                    .await;
+            }
+        });
+    }
+
+    async fn recreate(provider: &Provider) -> Client<Discovered, Claims> {
+        let primary = provider.name.is_none();
+        if primary {
+            OIDC_RECONNECTING.set(1);
+        }
+        let client = Self::discover(provider).await;
+        provider.save_cache(&client).await;
+        if primary {
+            OIDC_RECONNECTING.set(0);
+            OIDC_LAST_DISCOVERY.set(Utc::now().timestamp());
+            OIDC_JWKS_KEYS.set(client.jwks.as_ref().map(|x| x.keys.len()).unwrap_or(0) as i64);
+        }
+        client
+    }
+
+    async fn discover(provider: &Provider) -> Client<Discovered, Claims> {
+        let mut attempt = 0u32;
         loop {
             match Client::<Discovered, Claims>::discover_with_client(
                 HTTP_CLIENT.clone(),
-                CONFIG.client_id.to_string(),
-                CONFIG.client_secret.to_string(),
+                provider.client_id.clone(),
+                provider.client_secret.clone(),
                 Some(REDIRECT_URL.to_string()),
-                CONFIG.issuer.clone(),
+                provider.issuer.clone(),
             )
             .await
             {
                 Ok(x) => break x,
                 Err(e) => {
-                    warn!("failed to discover OIDC: {e:?}");
-                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    if attempt % CONFIG.oidc_retry_warn_every.max(1) == 0 {
+                        warn!(provider = ?provider.name, attempt, "failed to discover OIDC: {e:?}");
+                    }
+                    let delay = retry_delay(
+                        attempt,
+                        CONFIG.oidc_retry_base_sec,
+                        CONFIG.oidc_retry_max_sec,
+                        rand::random(),
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt = attempt.saturating_add(1);
                 }
             }
         }
     }
 
-    pub async fn auth_url(&self, redirect_uri: Url) -> Url {
-        let client = self.client.read().await;
-        let mut client = client.1.clone();
+    /// Clones the discovered client, swapping in the credentials of `registration` if given.
+    /// Discovery metadata and JWKS are shared since all registrations live at the same issuer.
+    fn registered(
+        client: &Client<Discovered, Claims>,
+        registration: Option<&ClientRegistration>,
+    ) -> Client<Discovered, Claims> {
+        let mut client = client.clone();
+        if let Some(registration) = registration {
+            client.client_id = registration.client_id.clone();
+            client.client_secret = registration.client_secret.clone();
+        }
+        client
+    }
+
+    pub async fn auth_url(
+        &self,
+        redirect_uri: Url,
+        state: &str,
+        nonce: &str,
+        code_challenge: &str,
+        registration: Option<&ClientRegistration>,
+    ) -> Url {
+        let mut client = Self::registered(&self.client.read().await.1, registration);
         client.redirect_uri = Some(redirect_uri.to_string());
-        client.auth_url(&OIDC_OPTIONS)
+        let options = Options {
+            scope: Some(self.provider.scopes.clone()),
+            state: None,
+            nonce: Some(nonce.to_string()),
+            ..Default::default()
+        };
+        let mut url = client.auth_url(&options);
+
+        let pairs = url
+            .query_pairs()
+            .into_owned()
+            .filter(|(name, _)| name != "response_type" && name != "state")
+            .collect::<Vec<_>>();
+        url.query_pairs_mut()
+            .clear()
+            .extend_pairs(pairs)
+            .append_pair("response_type", &CONFIG.response_type)
+            .append_pair("state", state)
+            .append_pair("code_challenge", code_challenge)
+            .append_pair("code_challenge_method", "S256");
+        url
     }
 
-    pub async fn renew(&self, token: Bearer) -> Result<(Bearer, Claims)> {
-        let client = self.client.read().await;
-        let mut token: Token<Claims> = client.1.refresh_token(token, None).await?.into();
+    pub async fn renew(
+        &self,
+        token: Bearer,
+        registration: Option<&ClientRegistration>,
+    ) -> Result<(Bearer, Claims)> {
+        self.check_cache_age()?;
+        let client = Self::registered(&self.client.read().await.1, registration);
+        let previous = token.clone();
+        let timer = OIDC_REFRESH_SECONDS.start_timer();
+        let refreshed = client
+            .refresh_token(token, Some(&*self.provider.scopes))
+            .await;
+        timer.observe_duration();
+        let mut token: Token<Claims> = refreshed?.into();
+        token.bearer = renewed_bearer(&previous, token.bearer);
         if let Some(id_token) = &mut token.id_token {
             client
-                .1
                 .decode_token(id_token)
                 .context("failed to decode token")?;
+            check_audience(id_token, &client.client_id)?;
             client
-                .1
                 .validate_token(id_token, None, None)
                 .context("failed to validate token")?;
         } else {
@@ -248,38 +597,62 @@ impl OidcHandler {
         Ok((token.bearer, token.id_token.unwrap().unwrap_decoded().1))
     }
 
-    pub async fn validate_code(&self, redirect_uri: &Url, code: &str) -> Result<(Bearer, Claims)> {
-        let mut client = self.client.read().await;
-        let now = Utc::now();
-        if client.0 < now {
-            drop(client);
-            let span = tracing::debug_span!("OIDC reconnect");
-            let mut old_client = self.client.write().instrument(span.clone()).await;
-            if old_client.0 < now {
-                let new_client = Self::recreate().instrument(span).await;
-                *old_client = (
-                    now + chrono::Duration::seconds(CONFIG.oidc_refresh_time_sec as i64),
-                    new_client,
-                )
-            }
-            drop(old_client);
-            client = self.client.read().await;
-        }
-        let mut client = client.1.clone();
+    /// The provider's RP-initiated logout endpoint, if discovery advertised one.
+    pub async fn end_session_endpoint(&self) -> Option<Url> {
+        let client = self.client.read().await;
+        client.1.config().end_session_endpoint.clone()
+    }
+
+    /// Fetches the raw userinfo claims for an access token.
+    pub async fn userinfo(&self, access_token: &str) -> Result<serde_json::Value> {
+        self.check_cache_age()?;
+        let client = self.client.read().await;
+        let endpoint = client
+            .1
+            .config()
+            .userinfo_endpoint
+            .clone()
+            .context("provider has no userinfo endpoint")?;
+        drop(client);
+        let userinfo = HTTP_CLIENT
+            .get(endpoint)
+            .bearer_auth(access_token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(userinfo)
+    }
+
+    pub async fn validate_code(
+        &self,
+        redirect_uri: &Url,
+        code: &str,
+        code_verifier: &str,
+        nonce: &str,
+        registration: Option<&ClientRegistration>,
+    ) -> Result<(Bearer, Claims)> {
+        self.check_cache_age()?;
+        // clone out of the lock, so a refresh swapping the client in never waits on the token endpoint
+        let mut client = Self::registered(&self.client.read().await.1, registration);
         client.redirect_uri = Some(redirect_uri.to_string());
-        let mut token: Token<Claims> = client
-            .request_token(code)
-            .await
-            .context("failed to resolve token")?
-            .into();
+        let timer = OIDC_TOKEN_EXCHANGE_SECONDS.start_timer();
+        let exchanged = request_token(&client, code, code_verifier).await;
+        timer.observe_duration();
+        let mut token: Token<Claims> = exchanged.context("failed to resolve token")?.into();
 
         if let Some(id_token) = &mut token.id_token {
             client
                 .decode_token(id_token)
                 .context("failed to decode token")?;
+            check_audience(id_token, &client.client_id)?;
             client
-                .validate_token(id_token, None, None)
+                .validate_token(id_token, Some(nonce), None)
                 .context("failed to validate token")?;
+            if CONFIG.require_token_hash {
+                check_token_hashes(id_token, &token.bearer.access_token, code)?;
+            }
         } else {
             bail!("no id token");
         };
@@ -287,3 +660,210 @@ impl OidcHandler {
         Ok((token.bearer, token.id_token.unwrap().unwrap_decoded().1))
     }
 }
+
+/// When to rediscover, `oidc_refresh_time_sec` after `now` give or take `oidc_refresh_jitter_percent`.
+fn refresh_deadline(now: DateTime<Utc>) -> DateTime<Utc> {
+    let secs = jittered(
+        CONFIG.oidc_refresh_time_sec as f64,
+        CONFIG.oidc_refresh_jitter_percent,
+        rand::random(),
+    );
+    now + chrono::Duration::milliseconds((secs * 1000.0) as i64)
+}
+
+/// `value` moved by up to `percent` either way, with `random` in `[0, 1)` picking where.
+fn jittered(value: f64, percent: f64, random: f64) -> f64 {
+    value * (1.0 + percent / 100.0 * (2.0 * random - 1.0))
+}
+
+/// Exponential backoff capped at `max`, scaled into `[1/2, 1]` of itself by `jitter` in `[0, 1)` so replicas don't retry in lockstep.
+fn retry_delay(attempt: u32, base: f64, max: f64, jitter: f64) -> Duration {
+    let backoff = (base * 2f64.powi(attempt.min(32) as i32)).min(max);
+    Duration::from_secs_f64(backoff * (0.5 + jitter / 2.0))
+}
+
+/// Authorization code exchange with a PKCE `code_verifier`, which `Client::request_token` can't send.
+async fn request_token(
+    client: &Client<Discovered, Claims>,
+    code: &str,
+    code_verifier: &str,
+) -> Result<Bearer> {
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("code_verifier", code_verifier),
+    ];
+    if let Some(redirect_uri) = &client.redirect_uri {
+        form.push(("redirect_uri", redirect_uri.as_str()));
+    }
+    let response = HTTP_CLIENT
+        .post(client.config().token_endpoint.clone())
+        .basic_auth(&client.client_id, Some(&client.client_secret))
+        .form(&form)
+        .send()
+        .await?;
+    let status = response.status();
+    let body = response.bytes().await?;
+    if !status.is_success() {
+        // keep OAuth errors recognizable to `TokenEndpointError::find`
+        if let Ok(e) = serde_json::from_slice::<OAuth2Error>(&body) {
+            return Err(ClientError::OAuth2(e).into());
+        }
+        bail!("token endpoint returned {status}");
+    }
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// OIDC `at_hash`/`c_hash`: the left half of the id_token algorithm's hash, base64url encoded.
+fn token_hash(algorithm: SignatureAlgorithm, value: &str) -> String {
+    let digest = match algorithm {
+        SignatureAlgorithm::HS384
+        | SignatureAlgorithm::RS384
+        | SignatureAlgorithm::ES384
+        | SignatureAlgorithm::PS384 => Sha384::digest(value).to_vec(),
+        SignatureAlgorithm::HS512
+        | SignatureAlgorithm::RS512
+        | SignatureAlgorithm::ES512
+        | SignatureAlgorithm::PS512 => Sha512::digest(value).to_vec(),
+        _ => Sha256::digest(value).to_vec(),
+    };
+    general_purpose::URL_SAFE_NO_PAD.encode(&digest[..digest.len() / 2])
+}
+
+/// The bearer to store after a refresh. Providers that rotate refresh tokens return a new one, which must replace the
+/// now invalidated old one; providers that don't rotate may omit it, in which case the old one stays valid and is kept.
+pub fn renewed_bearer(previous: &Bearer, mut refreshed: Bearer) -> Bearer {
+    if refreshed.refresh_token.is_none() {
+        refreshed.refresh_token = previous.refresh_token.clone();
+    }
+    refreshed
+}
+
+/// Rejects a decoded id_token issued for someone else: its `aud` must name `client_id` and each of `expected_audiences`.
+fn check_audience(id_token: &IdToken<Claims>, client_id: &str) -> Result<()> {
+    let claims = serde_json::to_value(id_token.payload().context("failed to read id_token")?)?;
+    let aud = claims.get("aud").map(claim_strings).unwrap_or_default();
+    if let Some(missing) = missing_audience(&aud, client_id, &CONFIG.expected_audiences) {
+        bail!("id_token audience {aud:?} does not include '{missing}'");
+    }
+    Ok(())
+}
+
+fn missing_audience<'a>(
+    aud: &[String],
+    client_id: &'a str,
+    expected: &'a [String],
+) -> Option<&'a str> {
+    std::iter::once(client_id)
+        .chain(expected.iter().map(|x| &**x))
+        .find(|x| !aud.iter().any(|a| a == x))
+}
+
+/// Rejects a decoded id_token whose `at_hash`/`c_hash`, where present, don't bind to the received access token and code.
+fn check_token_hashes(id_token: &IdToken<Claims>, access_token: &str, code: &str) -> Result<()> {
+    let algorithm = id_token
+        .header()
+        .context("failed to read id_token header")?
+        .registered
+        .algorithm;
+    let claims = serde_json::to_value(id_token.payload().context("failed to read id_token")?)?;
+    for (claim, value) in [("at_hash", access_token), ("c_hash", code)] {
+        if let Some(expected) = claims.get(claim).and_then(|x| x.as_str()) {
+            if expected != token_hash(algorithm, value) {
+                bail!("id_token {claim} mismatch");
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_discovery_cache() {
+        let issuer = Url::parse("https://idp.my.domain/realms/x").unwrap();
+        let config: openid::Config = serde_json::from_value(serde_json::json!({
+            "issuer": issuer,
+            "authorization_endpoint": "https://idp.my.domain/realms/x/auth",
+            "token_endpoint": "https://idp.my.domain/realms/x/token",
+            "userinfo_endpoint": "https://idp.my.domain/realms/x/userinfo",
+            "jwks_uri": "https://idp.my.domain/realms/x/certs",
+            "response_types_supported": ["code"],
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": ["RS256"],
+        }))
+        .unwrap();
+        let raw = serde_json::to_vec(&DiscoveryCache {
+            issuer: issuer.clone(),
+            fetched_at: 1000,
+            config,
+            jwks: None,
+        })
+        .unwrap();
+
+        let cache = DiscoveryCache::parse(&raw, &issuer, 3600, 1000 + 3600)
+            .unwrap()
+            .unwrap();
+        assert_eq!(cache.fetched_at, 1000);
+        assert_eq!(
+            cache.config.token_endpoint.as_str(),
+            "https://idp.my.domain/realms/x/token"
+        );
+
+        // too old, or written for another issuer
+        assert!(DiscoveryCache::parse(&raw, &issuer, 3600, 1000 + 3601)
+            .unwrap()
+            .is_none());
+        let other = Url::parse("https://idp.my.domain/realms/y").unwrap();
+        assert!(DiscoveryCache::parse(&raw, &other, 3600, 1000)
+            .unwrap()
+            .is_none());
+        assert!(DiscoveryCache::parse(b"{", &issuer, 3600, 1000).is_err());
+    }
+
+    #[test]
+    fn test_missing_audience() {
+        let aud = vec!["my_client".to_string(), "my_api".to_string()];
+        assert_eq!(missing_audience(&aud, "my_client", &[]), None);
+        assert_eq!(
+            missing_audience(&aud, "my_client", &["my_api".to_string()]),
+            None
+        );
+        assert_eq!(
+            missing_audience(&aud, "other_client", &[]),
+            Some("other_client")
+        );
+        assert_eq!(
+            missing_audience(&aud, "my_client", &["billing".to_string()]),
+            Some("billing")
+        );
+        assert_eq!(missing_audience(&[], "my_client", &[]), Some("my_client"));
+    }
+
+    #[test]
+    fn test_jittered() {
+        assert_eq!(jittered(3600.0, 10.0, 0.0), 3240.0);
+        assert_eq!(jittered(3600.0, 10.0, 0.5), 3600.0);
+        assert!(jittered(3600.0, 10.0, 1.0 - f64::EPSILON) < 3960.0);
+        assert_eq!(jittered(3600.0, 0.0, 0.9), 3600.0);
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let delays = (0..8)
+            .map(|x| {
+                retry_delay(x, 1.0, 60.0, 1.0 - f64::EPSILON)
+                    .as_secs_f64()
+                    .round()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(delays, [1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 60.0, 60.0]);
+        assert_eq!(retry_delay(3, 1.0, 60.0, 0.0), Duration::from_secs(4));
+        assert_eq!(
+            retry_delay(u32::MAX, 1.0, 60.0, 0.0),
+            Duration::from_secs(30)
+        );
+    }
+}
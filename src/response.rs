@@ -0,0 +1,17 @@
+use axol::Error;
+use axol_http::{response::Response, StatusCode};
+
+pub fn status_code(code: u16) -> Option<StatusCode> {
+    StatusCode::try_from(code).ok()
+}
+
+pub fn status_response(status: StatusCode) -> Response {
+    let mut response = Response::default();
+    response.status = status;
+    response
+}
+
+/// An error responding with `status`, for codes `Error` has no constructor for.
+pub fn status_error(status: StatusCode) -> Error {
+    Error::Response(status_response(status))
+}
@@ -0,0 +1,148 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use chrono::Utc;
+
+use crate::{config::CONFIG, jwt::JwtClaims};
+
+/// Denylist for sessions that must stop working before their cookie expires, e.g. after logout.
+pub trait RevocationStore: Send + Sync {
+    /// Revokes one session. It is forgotten after `exp`, when the session would have expired anyway.
+    fn revoke_session(&self, jti: &str, exp: i64);
+    /// Revokes every session of `sub` that logged in before now, for "log me out everywhere".
+    fn revoke_subject(&self, sub: &str);
+    fn is_revoked(&self, claims: &JwtClaims) -> bool;
+}
+
+/// Per-instance store. Revocations are lost on restart and not shared between replicas.
+#[derive(Default)]
+pub struct MemoryRevocations {
+    /// `jti` to the session's expiry.
+    sessions: Mutex<HashMap<String, i64>>,
+    /// `sub` to when it was revoked and until when that matters.
+    subjects: Mutex<HashMap<String, (i64, i64)>>,
+    max_session_sec: i64,
+    /// Sessions stay live this long past their expiry (`clock_skew_seconds`), so their revocations must too.
+    skew: i64,
+}
+
+impl MemoryRevocations {
+    pub fn new(max_session_sec: i64, skew: i64) -> Self {
+        Self {
+            max_session_sec,
+            skew,
+            ..Default::default()
+        }
+    }
+
+    /// Whether a revocation lasting until `until` still matters at `now`, matching `session_state`'s expiry.
+    fn in_force(&self, until: i64, now: i64) -> bool {
+        until + self.skew >= now
+    }
+
+    fn revoke_subject_at(&self, sub: &str, now: i64) {
+        let mut subjects = self.subjects.lock().unwrap();
+        subjects.retain(|_, (_, until)| self.in_force(*until, now));
+        // every session logged in by now expires by `now + max_session_sec`, since revoked sessions are never renewed
+        subjects.insert(sub.to_string(), (now, now + self.max_session_sec));
+    }
+
+    fn is_revoked_at(&self, claims: &JwtClaims, now: i64) -> bool {
+        if let Some(jti) = &claims.jti {
+            if let Some(exp) = self.sessions.lock().unwrap().get(jti) {
+                if self.in_force(*exp, now) {
+                    return true;
+                }
+            }
+        }
+        if let Some(sub) = &claims.sub {
+            if let Some((revoked_at, until)) = self.subjects.lock().unwrap().get(sub) {
+                if self.in_force(*until, now) && claims.login_at <= *revoked_at {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+impl RevocationStore for MemoryRevocations {
+    fn revoke_session(&self, jti: &str, exp: i64) {
+        let now = Utc::now().timestamp();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, exp| self.in_force(*exp, now));
+        sessions.insert(jti.to_string(), exp);
+    }
+
+    fn revoke_subject(&self, sub: &str) {
+        self.revoke_subject_at(sub, Utc::now().timestamp());
+    }
+
+    fn is_revoked(&self, claims: &JwtClaims) -> bool {
+        self.is_revoked_at(claims, Utc::now().timestamp())
+    }
+}
+
+lazy_static::lazy_static! {
+    pub static ref REVOCATIONS: Box<dyn RevocationStore> =
+        Box::new(MemoryRevocations::new(CONFIG.login_cache_minutes * 60, CONFIG.clock_skew_seconds));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(jti: &str, sub: &str, login_at: i64) -> JwtClaims {
+        serde_json::from_value(serde_json::json!({
+            "issuer": "https://my.domain/oi/",
+            "claims": {},
+            "iss": login_at,
+            "exp": login_at + 3600,
+            "roles": [],
+            "jti": jti,
+            "sub": sub,
+            "login_at": login_at,
+            "access_token": "",
+            "token_type": "Bearer",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_revocations() {
+        let store = MemoryRevocations::new(3600, 0);
+        let now = Utc::now().timestamp();
+        let laptop = claims("laptop", "alice", now - 60);
+        let phone = claims("phone", "alice", now - 60);
+        assert!(!store.is_revoked(&laptop));
+
+        store.revoke_session("laptop", laptop.exp);
+        assert!(store.is_revoked(&laptop));
+        assert!(!store.is_revoked(&phone));
+        // forgotten once the session would have expired anyway
+        assert!(!store.is_revoked_at(&laptop, laptop.exp + 1));
+
+        store.revoke_subject_at("alice", now);
+        assert!(store.is_revoked(&phone));
+        assert!(!store.is_revoked(&claims("bob", "bob", now - 60)));
+        // logging in again afterwards works
+        assert!(!store.is_revoked(&claims("new", "alice", now + 1)));
+        assert!(!store.is_revoked_at(&phone, now + 3601));
+    }
+
+    #[test]
+    fn test_revocations_within_skew() {
+        let store = MemoryRevocations::new(3600, 30);
+        let now = Utc::now().timestamp();
+        // expired by the clock, but still live for `session_state` until `exp + 30`
+        let laptop = claims("laptop", "alice", now - 3610);
+        store.revoke_session("laptop", laptop.exp);
+        assert!(store.is_revoked(&laptop));
+        assert!(store.is_revoked_at(&laptop, laptop.exp + 30));
+        assert!(!store.is_revoked_at(&laptop, laptop.exp + 31));
+
+        store.revoke_subject_at("bob", now);
+        let phone = claims("phone", "bob", now - 60);
+        assert!(store.is_revoked_at(&phone, now + 3600 + 30));
+        assert!(!store.is_revoked_at(&phone, now + 3600 + 31));
+    }
+}
@@ -0,0 +1,93 @@
+use axol::{Error, Query, Result, Typed};
+use axol_http::{header::HeaderMap, typed_headers::Cookie as CookieHeader, StatusCode};
+use serde::Deserialize;
+use tracing::{info, warn};
+use url::Url;
+
+use crate::{
+    config::{live, CONFIG},
+    response::status_error,
+    revocation::REVOCATIONS,
+    validate::{session_claims, session_state, SessionState},
+};
+
+#[derive(Deserialize)]
+pub struct RevokeParameters {
+    sub: String,
+}
+
+/// Whether the request's `Origin`, or failing that its `Referer`, is the origin of `public`.
+/// Browsers send one of them with cross-site POSTs, so this keeps other sites from riding on the admin's cookie.
+fn same_origin(headers: &HeaderMap, public: &Url) -> bool {
+    let expected = public.origin().ascii_serialization();
+    match headers.get("origin") {
+        Some(origin) => origin == expected,
+        None => headers
+            .get("referer")
+            .and_then(|x| Url::parse(x).ok())
+            .is_some_and(|x| x.origin().ascii_serialization() == expected),
+    }
+}
+
+/// Revokes every session of `sub`. The caller's own session must hold `revoke_required_roles`.
+pub async fn revoke(
+    Query(query): Query<RevokeParameters>,
+    cookies: Option<Typed<CookieHeader>>,
+    headers_in: HeaderMap,
+) -> Result<()> {
+    if !CONFIG.session_revocation || CONFIG.revoke_required_roles.is_empty() {
+        return Err(status_error(StatusCode::NotFound));
+    }
+    if !same_origin(&headers_in, &CONFIG.public) {
+        warn!("rejecting /revoke without a same-origin Origin or Referer");
+        return Err(Error::Forbidden);
+    }
+    let admin = session_claims(cookies.as_ref().map(|x| &x.0), false)?;
+    match session_state(&admin, &live().uncustomized()) {
+        SessionState::Live => (),
+        SessionState::Forbidden => return Err(Error::Forbidden),
+        SessionState::Expired | SessionState::Revoked => {
+            return Err(Error::unauthorized("expired token"))
+        }
+    }
+    let required: Vec<&str> = CONFIG.revoke_required_roles.iter().map(|x| &**x).collect();
+    if !admin.has_required_roles(&required, &[]) {
+        return Err(Error::Forbidden);
+    }
+    info!(admin = ?admin.sub, sub = %query.sub, "revoking all sessions");
+    REVOCATIONS.revoke_subject(&query.sub);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_same_origin() {
+        let public = Url::parse("https://my.domain/oi/").unwrap();
+        let headers = |name: &'static str, value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, value);
+            headers
+        };
+        assert!(!same_origin(&HeaderMap::new(), &public));
+        assert!(same_origin(
+            &headers("origin", "https://my.domain"),
+            &public
+        ));
+        assert!(!same_origin(
+            &headers("origin", "https://evil.domain"),
+            &public
+        ));
+        assert!(!same_origin(&headers("origin", "null"), &public));
+        assert!(same_origin(
+            &headers("referer", "https://my.domain/oi/admin"),
+            &public
+        ));
+        assert!(!same_origin(
+            &headers("referer", "https://my.domain.evil.domain/"),
+            &public
+        ));
+    }
+}
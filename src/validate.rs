@@ -1,51 +1,274 @@
-use axol::{ConnectInfo, Error, Result, Typed};
-use axol_http::{header::HeaderMap, typed_headers::Cookie as CookieHeader};
+use std::collections::HashMap;
+
+use axol::{ConnectInfo, Error, IntoResponse, Result, Typed};
+use axol_http::{
+    header::HeaderMap, response::Response, typed_headers::Cookie as CookieHeader, StatusCode,
+};
+use base64::{engine::general_purpose, Engine as _};
 use chrono::Utc;
 use cookie::Cookie;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 use url::Url;
 
 use crate::{
-    auth::build_cookie,
-    config::{Customized, CONFIG},
-    jwt::JwtClaims,
+    auth::{build_cookies, cookie_names, cookie_secure, strip_tokens},
+    chunks,
+    claims::{
+        allowed_claims, claim_values, granted_scopes, normalize_email_claim, refreshed_roles,
+        render_template, sanitize_header,
+    },
+    config::{
+        live, ConflictingHeaderPolicy, Customized, ForwardedHeadersMode, HeaderSanitize,
+        MissingClaimPolicy, RolesHeaderMode, ValidateErrorFormat, CONFIG,
+    },
+    jwt::{JwtClaims, UnknownKid},
     jwtc::decompress,
-    oidc::OIDC,
+    login::login_url,
+    metrics::VALIDATE_OUTCOMES,
+    oidc::{provider, TokenEndpointError},
+    response::{status_code, status_error, status_response},
+    revocation::REVOCATIONS,
+    seal::open,
 };
 
 enum PostValidation {
     Expired,
+    Revoked,
     Forbidden,
-    Renewed(Cookie<'static>, JwtClaims),
+    Renewed(Vec<Cookie<'static>>, JwtClaims),
     Pass(JwtClaims),
 }
 
+pub enum SessionState {
+    Live,
+    Expired,
+    Revoked,
+    Forbidden,
+}
+
+/// Checks a session against expiry and the customization's requirements, without renewing it.
+pub fn session_state(claims: &JwtClaims, customized: &Customized<'_>) -> SessionState {
+    let now = Utc::now().timestamp();
+    let skew = CONFIG.clock_skew_seconds;
+    if expired(claims.exp, now, skew)
+        || expired(claims.iss + CONFIG.login_cache_minutes * 60, now, skew)
+        || absolute_deadline(claims).is_some_and(|x| expired(x, now, skew))
+    {
+        return SessionState::Expired;
+    }
+    if CONFIG.session_revocation && REVOCATIONS.is_revoked(claims) {
+        return SessionState::Revoked;
+    }
+    if !claims.has_required_roles(&customized.required_roles, &customized.any_of_roles)
+        || !claims.has_required_scopes(&customized.required_scopes)
+        || !claims.has_required_claims(&customized.required_claims[..])
+    {
+        return SessionState::Forbidden;
+    }
+    SessionState::Live
+}
+
+pub enum SessionError {
+    MissingCookies,
+    NoCookie,
+    Malformed,
+    Invalid,
+    UnknownKid,
+    BadIssuer,
+}
+
+impl SessionError {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            SessionError::MissingCookies => "missing cookies",
+            SessionError::NoCookie => "no cookie set",
+            SessionError::Malformed => "malformed jwt",
+            SessionError::Invalid => "invalid jwt",
+            SessionError::UnknownKid => "unknown kid",
+            SessionError::BadIssuer => "bad issuer",
+        }
+    }
+}
+
+impl From<SessionError> for Error {
+    fn from(value: SessionError) -> Self {
+        match value {
+            SessionError::Malformed | SessionError::Invalid => Error::bad_request(value.reason()),
+            _ => Error::unauthorized(value.reason()),
+        }
+    }
+}
+
+/// Extracts and verifies the session JWT from the request cookies.
+/// Browsers send every cookie matching the request, so the same name can occur more than once (e.g. a stale cookie for another path or domain).
+pub fn session_claims(
+    cookies: Option<&CookieHeader>,
+    accept_any_issuer: bool,
+) -> std::result::Result<JwtClaims, SessionError> {
+    let header = cookies.ok_or(SessionError::MissingCookies)?;
+    let cookies = header.iter().collect::<Vec<_>>();
+    let max_chunks = chunks::max_chunks(CONFIG.max_total_cookie_bytes, CONFIG.cookie_max_size);
+    pick_cookie_session(&cookies, &CONFIG.cookie_name, max_chunks, |value| {
+        // cookies from before `cookie_encrypt` was enabled are still signed, so they're accepted as they are
+        let opened = if CONFIG.cookie_encrypt {
+            open(value).ok()
+        } else {
+            None
+        };
+        let decompressed =
+            decompress(opened.as_deref().unwrap_or(value)).map_err(|_| SessionError::Malformed)?;
+        let claims = JwtClaims::validate(&decompressed).map_err(|e| {
+            if e.is::<UnknownKid>() {
+                SessionError::UnknownKid
+            } else {
+                SessionError::Invalid
+            }
+        })?;
+        if !accept_any_issuer && claims.issuer != CONFIG.public {
+            return Err(SessionError::BadIssuer);
+        }
+        Ok(claims)
+    })
+}
+
+/// The session among the request's `cookies` named `cookie_name`, chunked or not, per `pick_session`.
+fn pick_cookie_session<T>(
+    cookies: &[(&str, &str)],
+    cookie_name: &str,
+    max_chunks: usize,
+    verify: impl Fn(&str) -> std::result::Result<T, SessionError>,
+) -> std::result::Result<T, SessionError> {
+    // a complete chunked session takes precedence over a single cookie left from before it outgrew one
+    let chunked = chunks::reassemble(cookies.iter().copied(), cookie_name, max_chunks);
+    let singles = cookies
+        .iter()
+        .filter(|(name, _)| *name == cookie_name)
+        .map(|(_, value)| *value);
+    let candidates = chunked.as_deref().into_iter().chain(singles);
+    pick_session(candidates, verify)
+}
+
+/// Deterministic precedence among candidate session cookies: the first that verifies wins, otherwise the first one's error is reported.
+fn pick_session<'a, T>(
+    candidates: impl IntoIterator<Item = &'a str>,
+    verify: impl Fn(&str) -> std::result::Result<T, SessionError>,
+) -> std::result::Result<T, SessionError> {
+    let mut first_error = None;
+    for candidate in candidates {
+        match verify(candidate) {
+            Ok(x) => return Ok(x),
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    Err(first_error.unwrap_or(SessionError::NoCookie))
+}
+
+/// Seconds until the session expires, by its `exp`, the login cache window, or `absolute_session_seconds`.
+pub fn session_ttl(claims: &JwtClaims) -> i64 {
+    let expires = claims
+        .exp
+        .min(claims.iss + CONFIG.login_cache_minutes * 60)
+        .min(absolute_deadline(claims).unwrap_or(i64::MAX));
+    expires - Utc::now().timestamp()
+}
+
+/// When the session must log in again regardless of renewals, if `absolute_session_seconds` is set.
+/// Sessions minted before `login_at` was recorded count from their `iss`, which their first renewal pins as `login_at`.
+fn absolute_deadline(claims: &JwtClaims) -> Option<i64> {
+    let login_at = if claims.login_at > 0 {
+        claims.login_at
+    } else {
+        claims.iss
+    };
+    CONFIG.absolute_session_seconds.map(|x| login_at + x)
+}
+
 async fn postvalidate_jwt(
     mut claims: JwtClaims,
     customized: &Customized<'_>,
-) -> Result<PostValidation> {
+    present: &[&str],
+    secure: bool,
+) -> anyhow::Result<PostValidation> {
     let now = Utc::now().timestamp();
-    if claims.exp < now || claims.iss + CONFIG.login_cache_minutes * 60 < now {
-        return Ok(PostValidation::Expired);
+    let mut roles_refreshed = false;
+    if let Some(interval) = CONFIG.role_refresh_sec {
+        if !matches!(
+            session_state(&claims, &live().uncustomized()),
+            SessionState::Expired | SessionState::Revoked
+        ) && !claims.bearer.access_token.is_empty()
+            && claims.roles_at + interval < now
+        {
+            match provider(claims.provider.as_deref())
+                .userinfo(&claims.bearer.access_token)
+                .await
+            {
+                Ok(userinfo) => {
+                    claims.roles = CONFIG.session_roles(&userinfo);
+                    claims.roles_at = now;
+                    roles_refreshed = true;
+                }
+                Err(e) => warn!("failed to refresh roles, using cached roles: {e:#}"),
+            }
+        }
     }
-    if !claims.has_required_roles(&customized.required_roles[..]) {
-        return Ok(PostValidation::Forbidden);
+    match session_state(&claims, customized) {
+        SessionState::Expired => return Ok(PostValidation::Expired),
+        SessionState::Revoked => return Ok(PostValidation::Revoked),
+        SessionState::Forbidden => return Ok(PostValidation::Forbidden),
+        SessionState::Live => (),
     }
     if CONFIG.refresh_tokens
         && claims.bearer.refresh_token.is_some()
-        && claims.iss + CONFIG.login_renew_seconds < now
+        && (due(
+            claims.iss + CONFIG.login_renew_seconds,
+            now,
+            CONFIG.clock_skew_seconds,
+        ) || expires_within(
+            claims.bearer.expires.map(|x| x.timestamp()),
+            CONFIG
+                .eager_refresh_seconds
+                .map(|x| x + CONFIG.clock_skew_seconds),
+            now,
+        ))
+        && claims.refreshed_at + CONFIG.min_refresh_interval_sec <= now
     {
         info!("renewing token");
-        let (bearer, new_claims) = OIDC.renew(claims.bearer).await?;
+        // customizations, and the client registrations in them, are reloadable
+        let live = live();
+        let registration = claims
+            .client_id
+            .as_deref()
+            .and_then(|x| live.client_registration(x));
+        let oidc = provider(claims.provider.as_deref());
+        let (bearer, new_claims) = oidc.renew(claims.bearer, registration).await?;
         claims.bearer = bearer;
-        claims.bearer.id_token.take();
-        claims.bearer.access_token = "".to_string();
-        claims.roles = new_claims
-            .realm_access
-            .as_ref()
-            .map(|x| &x.roles[..])
-            .unwrap_or_default()
-            .to_vec();
+        strip_tokens(&mut claims.bearer);
+        let raw_claims = serde_json::to_value(&new_claims)?;
+        claims.roles = refreshed_roles(
+            claims.roles,
+            CONFIG.session_roles(&raw_claims),
+            CONFIG.keep_roles_on_refresh,
+        );
+        claims.roles_at = now;
+        claims.refreshed_at = now;
+        claims.scopes = granted_scopes(
+            &raw_claims,
+            claims.bearer.scope.as_deref(),
+            &oidc.provider.scopes,
+        );
+        let refreshed_values = claim_values(&raw_claims, live.required_claim_names());
+        for name in claims.claim_values.keys() {
+            if !refreshed_values.contains_key(name) {
+                warn!("refreshed id_token is missing required claim {name}");
+            }
+        }
+        claims.claim_values = refreshed_values;
+        claims.userinfo = allowed_claims(&raw_claims, &CONFIG.userinfo_header_claims);
+        if CONFIG.normalize_email {
+            normalize_email_claim(&mut claims.userinfo);
+        }
 
         let now = Utc::now().timestamp();
         let mut max_age = CONFIG.login_cache_minutes * 60;
@@ -56,77 +279,697 @@ async fn postvalidate_jwt(
                 }
             }
         }
+        if let Some(deadline) = absolute_deadline(&claims) {
+            // still live within the skew window, but a renewal past the cap would mint an already-expired cookie
+            if deadline <= now {
+                return Ok(PostValidation::Expired);
+            }
+            max_age = max_age.min(deadline - now);
+        }
 
+        if claims.login_at == 0 {
+            // `iss` moves with every renewal, so without this such sessions would never reach the absolute cap
+            claims.login_at = claims.iss;
+        }
         claims.iss = now;
         claims.exp = claims.iss + max_age;
         return Ok(PostValidation::Renewed(
-            build_cookie(&claims, max_age)?,
+            build_cookies(&claims, max_age, present, secure)?,
+            claims,
+        ));
+    }
+    if roles_refreshed {
+        let max_age = claims.exp - now;
+        return Ok(PostValidation::Renewed(
+            build_cookies(&claims, max_age, present, secure)?,
             claims,
         ));
     }
     Ok(PostValidation::Pass(claims))
 }
 
+/// Rendered `header_claims`, sanitized again at emit time since sessions minted by older versions stored claims verbatim.
+fn claim_headers<'a>(
+    header_claims: &'a HashMap<String, String>,
+    claims: &JwtClaims,
+    missing: MissingClaimPolicy,
+    sanitize: HeaderSanitize,
+) -> Vec<(&'a str, String)> {
+    let lookup = |name: &str| match name {
+        "access_token" if !claims.bearer.access_token.is_empty() => {
+            Some(&*claims.bearer.access_token)
+        }
+        _ => claims.claims.get(name).map(|x| &**x),
+    };
+    header_claims
+        .iter()
+        .filter_map(|(header, template)| {
+            let value = render_template(template, lookup, missing)?;
+            Some((&**header, sanitize_header(&value, sanitize)?))
+        })
+        .collect()
+}
+
+/// Whether `deadline` has passed at `now`, giving the benefit of the doubt for up to `skew` seconds of clock drift.
+fn expired(deadline: i64, now: i64, skew: i64) -> bool {
+    deadline + skew < now
+}
+
+/// Whether work scheduled for `deadline` should happen at `now`, up to `skew` seconds ahead of time so drift can't make it late.
+fn due(deadline: i64, now: i64, skew: i64) -> bool {
+    deadline - skew < now
+}
+
+/// Whether an access token expiring at `expires` is within `window` seconds of it at `now`.
+fn expires_within(expires: Option<i64>, window: Option<i64>, now: i64) -> bool {
+    match (expires, window) {
+        (Some(expires), Some(window)) => expires - now <= window,
+        _ => false,
+    }
+}
+
+/// Reasoning behind a `/validate` decision, for `debug_decisions`.
+#[derive(Default)]
+struct DecisionTrace {
+    customizations: Vec<usize>,
+    required_roles: Vec<String>,
+    roles: Vec<String>,
+    failed: Option<&'static str>,
+    ttl: Option<i64>,
+    /// For `VALIDATE_OUTCOMES`, when not simply pass or unauthorized.
+    outcome: Option<&'static str>,
+    /// Owned, since the customizations naming it can change on reload.
+    hostname: String,
+}
+
+impl DecisionTrace {
+    fn header_value(&self) -> String {
+        let list = |items: &[String]| items.join(",");
+        format!(
+            "customizations={}; required_roles={}; roles={}; failed={}; ttl={}",
+            list(
+                &self
+                    .customizations
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<_>>()
+            ),
+            list(&self.required_roles),
+            list(&self.roles),
+            self.failed.unwrap_or("none"),
+            self.ttl
+                .map(|x| x.to_string())
+                .unwrap_or_else(|| "none".to_string()),
+        )
+    }
+}
+
+const DEBUG_HEADER: &str = "x-auth-debug";
+
 pub async fn validate(
     cookies: Option<Typed<CookieHeader>>,
     headers_in: HeaderMap,
     connect_info: ConnectInfo,
+) -> Result<Response> {
+    let debug = CONFIG.debug_decisions_for(connect_info.ip());
+    let mut trace = DecisionTrace::default();
+    let login = login_redirect(&headers_in);
+    let result = decide(cookies, headers_in, connect_info, &mut trace)
+        .await
+        .map(success_response);
+    let outcome = trace.outcome.unwrap_or(if result.is_ok() {
+        "pass"
+    } else {
+        "unauthorized"
+    });
+    VALIDATE_OUTCOMES
+        .with_label_values(&[outcome, &trace.hostname])
+        .inc();
+    let result = match (result, login) {
+        (Err(e), Some(login)) => {
+            let response = e.into_response()?;
+            Err(Error::Response(
+                if response.status == StatusCode::Unauthorized {
+                    let mut redirect = status_response(StatusCode::Found);
+                    redirect.headers.insert("location", login.to_string());
+                    redirect
+                } else {
+                    response
+                },
+            ))
+        }
+        (result, _) => result,
+    };
+    if !debug {
+        return result;
+    }
+    let value = trace.header_value();
+    match result {
+        Ok(mut response) => {
+            response.headers.insert(DEBUG_HEADER, value);
+            Ok(response)
+        }
+        Err(e) => {
+            let mut response = e.into_response()?;
+            response.headers.insert(DEBUG_HEADER, value);
+            Err(Error::Response(response))
+        }
+    }
+}
+
+/// A `/validate` 401 with a Bearer challenge and the body `validate_error_format` asks for.
+fn unauthorized(reason: &'static str) -> Error {
+    let shown = CONFIG.verbose_errors.then_some(reason);
+    let mut response = match CONFIG.validate_error_format {
+        ValidateErrorFormat::Text => {
+            match Error::unauthorized(shown.unwrap_or("unauthorized")).into_response() {
+                Ok(response) => response,
+                Err(e) => return e,
+            }
+        }
+        ValidateErrorFormat::Json => {
+            let mut body = serde_json::json!({ "error": "unauthorized" });
+            if let Some(reason) = shown {
+                body["reason"] = reason.into();
+            }
+            let mut response = status_response(StatusCode::Unauthorized);
+            response.headers.insert("content-type", "application/json");
+            response.body = body.to_string().into();
+            response
+        }
+    };
+    response.headers.insert(
+        "www-authenticate",
+        format!("Bearer realm=\"{}\"", CONFIG.www_authenticate_realm),
+    );
+    Error::Response(response)
+}
+
+/// The configured `validate_success_status` and body, carrying the headers `decide` computed.
+fn success_response(headers: HeaderMap) -> Response {
+    let status = status_code(CONFIG.validate_success_status).unwrap_or(StatusCode::Ok);
+    let mut response = status_response(status);
+    response.headers = headers;
+    if let Some(body) = &CONFIG.validate_success_body {
+        response.body = body.clone().into();
+    }
+    response
+}
+
+/// Where to send a browser that fails authentication, per `validate_redirect_to_login`.
+fn login_redirect(headers: &HeaderMap) -> Option<Url> {
+    if !CONFIG.validate_redirect_to_login
+        || !prefers_html(headers.get("accept").unwrap_or_default())
+    {
+        return None;
+    }
+    Some(login_url(&original_url(
+        headers,
+        CONFIG.forwarded_headers_mode,
+    )?))
+}
+
+/// Whether an `Accept` header ranks `text/html` at least as high as any other specific media type.
+/// A bare `*/*`, as sent by most API clients, doesn't count.
+fn prefers_html(accept: &str) -> bool {
+    let mut html = 0.0f32;
+    let mut other = 0.0f32;
+    for range in accept.split(',') {
+        let mut parts = range.split(';');
+        let media = parts.next().unwrap_or_default().trim();
+        let q = parts
+            .filter_map(|x| x.trim().strip_prefix("q="))
+            .find_map(|x| x.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        match media {
+            "text/html" | "application/xhtml+xml" => html = q.max(html),
+            "*/*" => (),
+            _ => other = other.max(q),
+        }
+    }
+    html > 0.0 && html >= other
+}
+
+/// Reconstructs the url the proxy is authorizing, from NGINX's `x-original-url` or Traefik's `x-forwarded-*` headers.
+fn original_url(headers: &HeaderMap, mode: ForwardedHeadersMode) -> Option<Url> {
+    let nginx = || Url::parse(headers.get("x-original-url")?).ok();
+    let traefik = || {
+        let proto = headers.get("x-forwarded-proto").unwrap_or("https");
+        let host = headers.get("x-forwarded-host")?;
+        let uri = headers.get("x-forwarded-uri").unwrap_or("/");
+        Url::parse(&format!("{proto}://{host}{uri}")).ok()
+    };
+    match mode {
+        ForwardedHeadersMode::Nginx => nginx(),
+        ForwardedHeadersMode::Traefik => traefik(),
+        ForwardedHeadersMode::Auto => nginx().or_else(traefik),
+    }
+}
+
+/// The method of the request being authorized, from NGINX's `x-original-method` or Traefik's `x-forwarded-method`.
+fn original_method(headers: &HeaderMap, mode: ForwardedHeadersMode) -> Option<&str> {
+    let nginx = || headers.get("x-original-method");
+    let traefik = || headers.get("x-forwarded-method");
+    match mode {
+        ForwardedHeadersMode::Nginx => nginx(),
+        ForwardedHeadersMode::Traefik => traefik(),
+        ForwardedHeadersMode::Auto => nginx().or_else(traefik),
+    }
+}
+
+/// Which header size limit, if any, `headers` exceeds.
+fn oversized_headers(headers: &HeaderMap) -> Option<&'static str> {
+    let mut total = 0;
+    let mut cookie = 0;
+    for (name, value) in headers.iter() {
+        total += name.len() + value.len();
+        if name.eq_ignore_ascii_case("cookie") {
+            cookie += value.len();
+        }
+    }
+    if cookie > CONFIG.max_cookie_header_bytes {
+        Some("cookie header too large")
+    } else if total > CONFIG.max_header_bytes {
+        Some("headers too large")
+    } else {
+        None
+    }
+}
+
+async fn decide(
+    cookies: Option<Typed<CookieHeader>>,
+    headers_in: HeaderMap,
+    connect_info: ConnectInfo,
+    trace: &mut DecisionTrace,
 ) -> Result<HeaderMap> {
-    let original_url = headers_in
-        .get("x-original-url")
-        .and_then(|x| Url::parse(x).ok());
+    trace.hostname = "other".to_string();
+    if let Some(reason) = oversized_headers(&headers_in) {
+        warn!(client = %connect_info.ip(), "rejecting /validate request: {reason}");
+        trace.failed = Some(reason);
+        trace.outcome = Some("error");
+        return Err(status_error(StatusCode::RequestHeaderFieldsTooLarge));
+    }
+
+    let live = live();
+    if CONFIG.conflicting_header_policy != ConflictingHeaderPolicy::Ignore {
+        if let Some(header) = live
+            .emitted_headers()
+            .find(|x| headers_in.get(*x).is_some())
+        {
+            warn!(
+                client = %connect_info.ip(),
+                "request already carries {header}, which only the trusted proxy may set"
+            );
+            if CONFIG.conflicting_header_policy == ConflictingHeaderPolicy::Reject {
+                trace.outcome = Some("error");
+                return Err(Error::bad_request("conflicting auth header"));
+            }
+        }
+    }
+
+    let original_url = original_url(&headers_in, CONFIG.forwarded_headers_mode);
+    let original_method = original_method(&headers_in, CONFIG.forwarded_headers_mode);
+
+    if let Some(host) = original_url.as_ref().and_then(|x| x.host_str()) {
+        trace.hostname = live.metrics_hostname(host).to_string();
+    }
+    if let Some(original_url) = &original_url {
+        if CONFIG.is_public_path(original_url.path()) {
+            trace.outcome = Some("bypass");
+            return Ok(HeaderMap::new());
+        }
+    }
 
+    let expected_provider = original_url
+        .as_ref()
+        .and_then(|x| CONFIG.provider_for_url(x));
     let customized = if let Some(original_url) = original_url {
-        CONFIG.customized(
-            original_url.host_str().unwrap_or_default(),
-            original_url.path(),
-            connect_info.ip(),
-        )
+        live.customized_url(&original_url, original_method, connect_info.ip())
     } else {
-        CONFIG.uncustomized()
+        live.uncustomized()
     };
 
+    trace.customizations = customized.matched.clone();
+    trace.required_roles = customized
+        .required_roles
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+
     if customized.bypass {
+        trace.outcome = Some("bypass");
         return Ok(HeaderMap::new());
     }
 
-    let claims = match &cookies {
-        None => return Err(Error::unauthorized("missing cookies")),
-        Some(header) => header
-            .0
-            .get(&CONFIG.cookie_name)
-            .ok_or_else(|| Error::unauthorized("no cookie set"))?,
+    let claims = session_claims(cookies.as_ref().map(|x| &x.0), customized.accept_any_issuer);
+    if let Err(e) = &claims {
+        trace.failed = Some(e.reason());
+    }
+    let claims = match claims {
+        Ok(claims) => claims,
+        Err(e @ (SessionError::Malformed | SessionError::Invalid))
+            if CONFIG.relogin_on_invalid_cookie =>
+        {
+            // e.g. a cookie signed by another instance mid-rollout: re-authenticate instead of erroring
+            info!("session cookie rejected ({}), sending to login", e.reason());
+            return Err(unauthorized(e.reason()));
+        }
+        Err(e @ (SessionError::Malformed | SessionError::Invalid)) => {
+            trace.outcome = Some("error");
+            return Err(e.into());
+        }
+        Err(e) => return Err(unauthorized(e.reason())),
     };
-    let decompressed = decompress(claims).map_err(|_| Error::bad_request("malformed jwt"))?;
-    let claims =
-        JwtClaims::validate(&decompressed).map_err(|_| Error::bad_request("invalid jwt"))?;
-
-    if claims.issuer != CONFIG.public {
-        return Err(Error::unauthorized("bad issuer"));
+    if claims.provider.as_deref() != expected_provider {
+        // the cookie is shared across hosts, but a session from one provider says nothing about another's users
+        trace.failed = Some("wrong provider");
+        return Err(unauthorized("wrong provider"));
     }
 
     let mut headers = HeaderMap::new();
 
-    let claims = match postvalidate_jwt(claims, &customized).await {
-        Err(e) => {
-            error!("postvalidation error: {e:#}");
-            return Err(Error::unauthorized("token invalid"));
+    trace.roles = claims.roles.clone();
+    trace.ttl = Some(session_ttl(&claims));
+    let has_roles = claims.has_required_roles(&customized.required_roles, &customized.any_of_roles);
+    let has_scopes = claims.has_required_scopes(&customized.required_scopes);
+
+    let present = cookie_names(cookies.as_ref().map(|x| &x.0));
+    let secure = cookie_secure(&headers_in);
+    let claims = match postvalidate_jwt(claims, &customized, &present, secure).await {
+        Err(e) => match TokenEndpointError::find(&e) {
+            Some(oauth) if oauth.is_invalid_client() => {
+                error!(
+                    oauth.error = %oauth.error,
+                    oauth.error_description = ?oauth.description,
+                    "token endpoint rejected our client credentials, check client_id/client_secret: {e:#}"
+                );
+                trace.failed = Some("renewal: invalid_client");
+                trace.outcome = Some("error");
+                return Err(status_error(StatusCode::ServiceUnavailable));
+            }
+            Some(oauth) => {
+                trace.failed = Some("renewal");
+                // invalid_grant and friends: the refresh token is no good, 401 sends the user through login again
+                warn!(
+                    oauth.error = %oauth.error,
+                    oauth.error_description = ?oauth.description,
+                    "token renewal rejected: {e:#}"
+                );
+                return Err(unauthorized("token invalid"));
+            }
+            None => {
+                trace.failed = Some("renewal");
+                error!("postvalidation error: {e:#}");
+                return Err(unauthorized("token invalid"));
+            }
+        },
+        Ok(PostValidation::Expired) => {
+            trace.failed = Some("expired");
+            trace.outcome = Some("expired");
+            return Err(unauthorized("expired token"));
+        }
+        Ok(PostValidation::Revoked) => {
+            trace.failed = Some("revoked");
+            trace.outcome = Some("expired");
+            return Err(unauthorized("revoked session"));
+        }
+        Ok(PostValidation::Forbidden) => {
+            let reason = if !has_roles {
+                "required_roles"
+            } else if !has_scopes {
+                "required_scopes"
+            } else {
+                "required_claims"
+            };
+            info!("access denied: missing {reason}");
+            trace.failed = Some(reason);
+            trace.outcome = Some("forbidden");
+            return Err(customized
+                .deny_status
+                .and_then(status_code)
+                .map(status_error)
+                .unwrap_or(Error::Forbidden));
         }
-        Ok(PostValidation::Expired) => return Err(Error::unauthorized("expired token")),
-        Ok(PostValidation::Forbidden) => return Err(Error::Forbidden),
-        Ok(PostValidation::Renewed(new_cookie, claims)) => {
-            headers.insert("set-cookie", new_cookie.encoded().to_string());
+        Ok(PostValidation::Renewed(new_cookies, claims)) => {
+            trace.outcome = Some("renewed");
+            for cookie in new_cookies {
+                headers.append("set-cookie", cookie.encoded().to_string());
+            }
             claims
         }
         Ok(PostValidation::Pass(claims)) => claims,
     };
 
-    headers.insert(&*CONFIG.success_header, "true");
-    for (header, claim) in &CONFIG.header_claims {
-        if let Some(value) = claims.claims.get(claim) {
-            headers.insert(&**header, value);
+    for header in &CONFIG.success_headers {
+        headers.insert(&**header, "true");
+    }
+    if let Some(header) = &CONFIG.userinfo_header {
+        if !claims.userinfo.is_empty() {
+            let json = serde_json::to_vec(&claims.userinfo).map_err(Error::internal)?;
+            headers.insert(&**header, general_purpose::STANDARD.encode(json));
+        }
+    }
+    if let Some(header) = &CONFIG.roles_header {
+        let roles = claims
+            .roles
+            .iter()
+            .filter(|x| {
+                CONFIG.roles_header_allowlist.is_empty()
+                    || CONFIG.roles_header_allowlist.contains(x)
+            })
+            .map(|x| &**x)
+            .collect::<Vec<_>>();
+        if !roles.is_empty() || !CONFIG.roles_header_omit_empty {
+            match CONFIG.roles_header_mode {
+                RolesHeaderMode::Joined => {
+                    let joined = roles.join(&CONFIG.roles_header_delimiter);
+                    if let Some(joined) = sanitize_header(&joined, CONFIG.header_sanitize) {
+                        headers.insert(&**header, joined);
+                    }
+                }
+                RolesHeaderMode::Repeated => {
+                    for role in roles {
+                        if let Some(role) = sanitize_header(role, CONFIG.header_sanitize) {
+                            headers.append(&**header, role);
+                        }
+                    }
+                }
+            }
         }
     }
+    if let Some(header) = &CONFIG.access_token_expires_header {
+        if let Some(expires) = claims.bearer.expires {
+            if !claims.bearer.access_token.is_empty() {
+                headers.insert(&**header, expires.timestamp().to_string());
+            }
+        }
+    }
+    if CONFIG.forward_access_token && !claims.bearer.access_token.is_empty() {
+        let token = &claims.bearer.access_token;
+        let value = if CONFIG
+            .access_token_header
+            .eq_ignore_ascii_case("authorization")
+        {
+            format!("Bearer {token}")
+        } else {
+            token.clone()
+        };
+        headers.insert(&*CONFIG.access_token_header, value);
+    }
+    for (header, value) in claim_headers(
+        &live.header_claims,
+        &claims,
+        live.header_claims_missing,
+        CONFIG.header_sanitize,
+    ) {
+        headers.insert(header, value);
+    }
 
     Ok(headers)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_claim_headers_sanitized() {
+        let claims: JwtClaims = serde_json::from_value(serde_json::json!({
+            "issuer": "https://my.domain/oi/",
+            "claims": {
+                "name": "Mallory\r\nX-Auth-Roles: admin",
+                "blank": "\r\n",
+            },
+            "iss": 0,
+            "exp": 3600,
+            "roles": [],
+            "access_token": "",
+            "token_type": "Bearer",
+        }))
+        .unwrap();
+        let header_claims = HashMap::from([
+            ("X-Oi-Name".to_string(), "name".to_string()),
+            ("X-Oi-Blank".to_string(), "blank".to_string()),
+        ]);
+
+        let headers = claim_headers(
+            &header_claims,
+            &claims,
+            MissingClaimPolicy::Omit,
+            HeaderSanitize::Strip,
+        );
+        assert_eq!(
+            headers,
+            vec![("X-Oi-Name", "MalloryX-Auth-Roles: admin".to_string())]
+        );
+
+        let headers = claim_headers(
+            &header_claims,
+            &claims,
+            MissingClaimPolicy::Omit,
+            HeaderSanitize::PercentEncode,
+        );
+        assert!(headers.contains(&("X-Oi-Name", "Mallory%0D%0AX-Auth-Roles: admin".to_string())));
+        assert!(headers.contains(&("X-Oi-Blank", "%0D%0A".to_string())));
+    }
+
+    #[test]
+    fn test_clock_skew() {
+        let deadline = 1000;
+        assert!(!expired(deadline, deadline + 29, 30));
+        assert!(!expired(deadline, deadline + 30, 30));
+        assert!(expired(deadline, deadline + 31, 30));
+        assert!(expired(deadline, deadline + 1, 0));
+
+        assert!(!due(deadline, deadline - 31, 30));
+        assert!(!due(deadline, deadline - 30, 30));
+        assert!(due(deadline, deadline - 29, 30));
+        assert!(!due(deadline, deadline, 0));
+        assert!(due(deadline, deadline + 1, 0));
+    }
+
+    #[test]
+    fn test_expires_within() {
+        assert!(expires_within(Some(1300), Some(300), 1000));
+        assert!(expires_within(Some(900), Some(300), 1000));
+        assert!(!expires_within(Some(1301), Some(300), 1000));
+        assert!(!expires_within(None, Some(300), 1000));
+        assert!(!expires_within(Some(1000), None, 1000));
+    }
+
+    #[test]
+    fn test_prefers_html() {
+        assert!(prefers_html(
+            "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"
+        ));
+        assert!(prefers_html("text/html"));
+        assert!(!prefers_html("application/json"));
+        assert!(!prefers_html("*/*"));
+        assert!(!prefers_html(""));
+        assert!(!prefers_html("application/json, text/html;q=0.5"));
+        assert!(!prefers_html("text/html;q=0"));
+    }
+
+    #[test]
+    fn test_original_url() {
+        let mut nginx = HeaderMap::new();
+        nginx.insert("x-original-url", "https://app.my.domain/a?b=c");
+        let mut traefik = HeaderMap::new();
+        traefik.insert("x-forwarded-proto", "https");
+        traefik.insert("x-forwarded-host", "app.my.domain");
+        traefik.insert("x-forwarded-uri", "/a?b=c");
+        traefik.insert("x-forwarded-method", "GET");
+
+        for (headers, mode, expected) in [
+            (&nginx, ForwardedHeadersMode::Auto, true),
+            (&nginx, ForwardedHeadersMode::Nginx, true),
+            (&nginx, ForwardedHeadersMode::Traefik, false),
+            (&traefik, ForwardedHeadersMode::Auto, true),
+            (&traefik, ForwardedHeadersMode::Traefik, true),
+            (&traefik, ForwardedHeadersMode::Nginx, false),
+        ] {
+            let url = original_url(headers, mode);
+            assert_eq!(url.is_some(), expected);
+            if let Some(url) = url {
+                assert_eq!(url.host_str(), Some("app.my.domain"));
+                assert_eq!(url.path(), "/a");
+                assert_eq!(url.query(), Some("b=c"));
+            }
+        }
+    }
+
+    #[test]
+    fn test_chunked_precedence() {
+        let cookies = [
+            ("oiplease", "legacy"),
+            ("oiplease.1", "-chunked"),
+            ("oiplease.0", "fresh"),
+        ];
+        let verify = |value: &str| Ok::<_, SessionError>(value.to_string());
+        assert_eq!(
+            pick_cookie_session(&cookies, "oiplease", 4, verify)
+                .ok()
+                .as_deref(),
+            Some("fresh-chunked")
+        );
+
+        // an invalid chunked session falls back to the single cookie
+        let verify = |value: &str| match value {
+            "fresh-chunked" => Err(SessionError::Invalid),
+            valid => Ok(valid.to_string()),
+        };
+        assert_eq!(
+            pick_cookie_session(&cookies, "oiplease", 4, verify)
+                .ok()
+                .as_deref(),
+            Some("legacy")
+        );
+
+        // incomplete chunks leave only the single cookie
+        let partial = [("oiplease", "legacy"), ("oiplease.1", "-chunked")];
+        let verify = |value: &str| Ok::<_, SessionError>(value.to_string());
+        assert_eq!(
+            pick_cookie_session(&partial, "oiplease", 4, verify)
+                .ok()
+                .as_deref(),
+            Some("legacy")
+        );
+        assert!(matches!(
+            pick_cookie_session(&[("other", "x")], "oiplease", 4, verify),
+            Err(SessionError::NoCookie)
+        ));
+
+        // rewriting the session as chunks expires the legacy single cookie
+        let present = cookies.map(|(name, _)| name);
+        assert_eq!(
+            chunks::stale(present, "oiplease", &chunks::names("oiplease", 2)),
+            vec!["oiplease"]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_cookies() {
+        let verify = |value: &str| match value {
+            "stale" => Err(SessionError::Invalid),
+            "garbage" => Err(SessionError::Malformed),
+            valid => Ok(valid.to_string()),
+        };
+
+        assert_eq!(
+            pick_session(["stale", "fresh"], verify).ok().as_deref(),
+            Some("fresh")
+        );
+        assert_eq!(
+            pick_session(["fresh", "stale"], verify).ok().as_deref(),
+            Some("fresh")
+        );
+        assert!(matches!(
+            pick_session(["garbage", "stale"], verify),
+            Err(SessionError::Malformed)
+        ));
+        assert!(matches!(
+            pick_session([], verify),
+            Err(SessionError::NoCookie)
+        ));
+    }
+}
@@ -0,0 +1,55 @@
+use axol::{Json, Typed};
+use axol_http::typed_headers::Cookie as CookieHeader;
+use serde::Serialize;
+
+use crate::{
+    config::live,
+    validate::{session_claims, session_state, session_ttl, SessionState},
+};
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    Valid,
+    Expired,
+    Forbidden,
+    Invalid,
+}
+
+#[derive(Serialize)]
+pub struct TokenStatus {
+    status: Verdict,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ttl_seconds: Option<i64>,
+    roles: Vec<String>,
+}
+
+/// Reports whether the session cookie passes the global (uncustomized) checks, without renewing it.
+/// Intended for synthetic monitoring of known sessions, so it needs no proxy headers.
+pub async fn validate_token(cookies: Option<Typed<CookieHeader>>) -> Json<TokenStatus> {
+    let claims = match session_claims(cookies.as_ref().map(|x| &x.0), false) {
+        Ok(claims) => claims,
+        Err(e) => {
+            return Json(TokenStatus {
+                status: Verdict::Invalid,
+                reason: Some(e.reason()),
+                ttl_seconds: None,
+                roles: vec![],
+            })
+        }
+    };
+    let (status, reason) = match session_state(&claims, &live().uncustomized()) {
+        SessionState::Live => (Verdict::Valid, None),
+        SessionState::Expired => (Verdict::Expired, Some("expired token")),
+        SessionState::Revoked => (Verdict::Expired, Some("revoked session")),
+        SessionState::Forbidden => (Verdict::Forbidden, Some("missing required roles or claims")),
+    };
+    Json(TokenStatus {
+        status,
+        reason,
+        ttl_seconds: Some(session_ttl(&claims).max(0)),
+        roles: claims.roles,
+    })
+}